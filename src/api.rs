@@ -0,0 +1,281 @@
+//! A versioned JSON API over the values we calculate, for bots and spreadsheets.
+//!
+//! Realm slugs aren't unique across regions, so every endpoint takes an optional
+//! `region` query parameter. It defaults to the first region we serve.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use iron::headers::ContentType;
+use iron::prelude::*;
+use iron::status;
+use router::Router;
+use serde::Serialize;
+use serde_json;
+
+use realm_reader::RegionState;
+use region::Region;
+use {query_param, CurrentRealmValues, PriceRow, Site};
+
+#[derive(Debug, Serialize)]
+struct ErrorReply {
+    error: String,
+}
+
+#[derive(Debug, Serialize)]
+struct RealmSummary {
+    slug: String,
+    name: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ConnectedRealmSummary {
+    id: u64,
+    /// When this connected realm's auctions were last updated, in milliseconds since the epoch. 0 if never.
+    last_update: u64,
+    realms: Vec<RealmSummary>,
+}
+
+#[derive(Debug, Serialize)]
+struct RegionSummary {
+    region: &'static str,
+    commodities_last_update: u64,
+    connected_realms: Vec<ConnectedRealmSummary>,
+}
+
+#[derive(Debug, Serialize)]
+struct RealmListReply {
+    regions: Vec<RegionSummary>,
+}
+
+#[derive(Debug, Serialize)]
+struct ValuesReply {
+    region: &'static str,
+    realm: String,
+    name: String,
+    connected_realm_id: u64,
+    /// When the stalest source these values depend on was updated. 0 if we're still waiting on one.
+    last_update: u64,
+    /// The rows of `currencies["blood"]`, kept from before currencies came from the catalog.
+    blood: Vec<PriceRow>,
+    /// The rows of `currencies["sargerite"]`, likewise.
+    sargerite: Vec<PriceRow>,
+    /// The realm page's rows for the items sold for each currency, keyed by currency id.
+    currencies: BTreeMap<String, Vec<PriceRow>>,
+}
+
+/// Estimated unit prices in copper, keyed by item id.
+#[derive(Debug, Serialize)]
+struct PriceMap {
+    last_update: u64,
+    prices: HashMap<u64, u64>,
+}
+
+impl<'a> From<&'a CurrentRealmValues> for PriceMap {
+    fn from(values: &'a CurrentRealmValues) -> PriceMap {
+        PriceMap {
+            last_update: values.last_update,
+            prices: (*values.value_map).clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct PricesReply {
+    region: &'static str,
+    realm: String,
+    connected_realm_id: u64,
+    commodities: PriceMap,
+    realm_auction_house: PriceMap,
+}
+
+fn json_response<T: Serialize>(status: status::Status, reply: &T) -> IronResult<Response> {
+    Ok(Response::with((ContentType::json().0, status, serde_json::to_string(reply).unwrap())))
+}
+
+fn not_found(error: String) -> IronResult<Response> {
+    json_response(status::NotFound, &ErrorReply { error: error })
+}
+
+/// Finds the region a request is about: the `region` query parameter, or the first region we serve.
+fn find_region<'a>(req: &Request, states: &'a [Arc<RegionState>]) -> Result<&'a Arc<RegionState>, String> {
+    match query_param(req.url.query(), "region") {
+        Some(name) => Region::from_name(name)
+            .and_then(|region| states.iter().find(|state| state.region == region))
+            .ok_or(format!("Unknown region {}", name)),
+        None => states.first().ok_or_else(|| "No regions are being served".to_owned()),
+    }
+}
+
+/// Finds the region and connected realm a `/api/v1/realms/:slug/...` request is about.
+fn find_realm<'a>(req: &Request, states: &'a [Arc<RegionState>]) -> Result<(&'a Arc<RegionState>, String, u64), String> {
+    let state = try!(find_region(req, states));
+    let slug = req.extensions.get::<Router>().unwrap().find("slug").unwrap().to_owned();
+    match state.realm_aliases.get(&slug) {
+        Some(&connected_realm_id) => Ok((state, slug, connected_realm_id)),
+        None => Err(format!("Unknown realm {} in {}", slug, state.region)),
+    }
+}
+
+/// A region's connected realms and when each was last updated, with realm names in `locale`.
+fn region_summary(state: &RegionState, locale: &str) -> RegionSummary {
+    RegionSummary {
+        region: state.region.info().name,
+        commodities_last_update: state.commodity_values.read().unwrap().last_update,
+        connected_realms: state.connected_realms.iter().map(|connected_realm| ConnectedRealmSummary {
+            id: connected_realm.id,
+            last_update: state.price_map.get(&connected_realm.id).unwrap().read().unwrap().last_update,
+            realms: connected_realm.realms.iter().map(|realm| RealmSummary {
+                slug: realm.slug.clone(),
+                name: realm.localized_name(locale).to_owned(),
+            }).collect(),
+        }).collect(),
+    }
+}
+
+/// The values of every catalog item on a realm, with names in `locale`.
+fn values_reply(site: &Site, state: &RegionState, slug: String, connected_realm_id: u64, locale: &str) -> ValuesReply {
+    let realm_prices = state.price_map.get(&connected_realm_id).unwrap().read().unwrap();
+    let commodity_prices = state.commodity_values.read().unwrap();
+    let catalog = site.catalog();
+    let sections = site.price_rows(&catalog, &realm_prices, &commodity_prices, locale);
+    let name = state.realms.iter().find(|realm| realm.slug == slug).unwrap().localized_name(locale).to_owned();
+    let currencies: BTreeMap<String, Vec<PriceRow>> = sections.into_iter()
+        .map(|section| (section.currency.id, section.price_rows))
+        .collect();
+    ValuesReply {
+        region: state.region.info().name,
+        realm: slug,
+        name: name,
+        connected_realm_id: connected_realm_id,
+        last_update: site.last_update(&catalog, &realm_prices, &commodity_prices),
+        blood: currencies.get("blood").cloned().unwrap_or_else(Vec::new),
+        sargerite: currencies.get("sargerite").cloned().unwrap_or_else(Vec::new),
+        currencies: currencies,
+    }
+}
+
+/// The estimated unit prices on a realm's auction house and the region's commodity auction house.
+fn prices_reply(state: &RegionState, slug: String, connected_realm_id: u64) -> PricesReply {
+    PricesReply {
+        region: state.region.info().name,
+        realm: slug,
+        connected_realm_id: connected_realm_id,
+        commodities: PriceMap::from(&*state.commodity_values.read().unwrap()),
+        realm_auction_house: PriceMap::from(&*state.price_map.get(&connected_realm_id).unwrap().read().unwrap()),
+    }
+}
+
+/// Mounts the `/api/v1` endpoints for every region we serve.
+pub fn add_api_routes(router: &mut Router, states: Vec<Arc<RegionState>>, site: Arc<Site>) {
+    let states = Arc::new(states);
+    {
+        let states = states.clone();
+        let site = site.clone();
+        router.get("/api/v1/realms", move |req: &mut Request| {
+            let selected: Vec<&Arc<RegionState>> = match query_param(req.url.query(), "region") {
+                Some(_) => match find_region(req, &states) {
+                    Ok(state) => vec![state],
+                    Err(e) => return not_found(e),
+                },
+                None => states.iter().collect(),
+            };
+            let regions = selected.into_iter().map(|state| region_summary(state, &site.locale_for(req, state))).collect();
+            json_response(status::Ok, &RealmListReply { regions: regions })
+        }, "api-realms");
+    }
+    {
+        let states = states.clone();
+        let site = site.clone();
+        router.get("/api/v1/realms/:slug/values", move |req: &mut Request| {
+            let (state, slug, connected_realm_id) = match find_realm(req, &states) {
+                Ok(realm) => realm,
+                Err(e) => return not_found(e),
+            };
+            let locale = site.locale_for(req, state);
+            json_response(status::Ok, &values_reply(&site, state, slug, connected_realm_id, &locale))
+        }, "api-realm-values");
+    }
+    router.get("/api/v1/realms/:slug/prices", move |req: &mut Request| {
+        let (state, slug, connected_realm_id) = match find_realm(req, &states) {
+            Ok(realm) => realm,
+            Err(e) => return not_found(e),
+        };
+        json_response(status::Ok, &prices_reply(state, slug, connected_realm_id))
+    }, "api-realm-prices");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::{self, Value};
+
+    use test_support::{fixture_region_state, fixture_site, TempDir, STARLIGHT_ROSE};
+    use ItemValue;
+    use super::{prices_reply, region_summary, values_reply};
+
+    const AETHRIL: u64 = 124101;
+
+    #[test]
+    fn test_replies() {
+        let history_dir = TempDir::new("blood-money-api-test");
+        let site = fixture_site(history_dir.path());
+        let state = fixture_region_state();
+        {
+            let mut values = state.commodity_values.write().unwrap();
+            values.last_update = 1509000000000;
+            values.value_map = Arc::new(vec![(STARLIGHT_ROSE, 1200)].into_iter().collect());
+            // Aethril has no item info, as if a catalog reload had just added it.
+            values.item_values = Arc::new(vec![("blood".to_owned(), vec![
+                ItemValue { id: STARLIGHT_ROSE, value: 3600, net_value: 3420, vendor_it: false },
+                ItemValue { id: AETHRIL, value: 1000, net_value: 950, vendor_it: false },
+            ])].into_iter().collect());
+        }
+        let slug = state.realms.iter().find(|realm| state.realm_aliases.get(&realm.slug) == Some(&61)).unwrap().slug.clone();
+
+        let values = serde_json::to_value(&values_reply(&site, &state, slug.clone(), 61, "en_US"));
+        for key in &["region", "realm", "name", "connected_realm_id", "last_update", "blood", "sargerite", "currencies"] {
+            assert!(values.find(key).is_some(), "values reply is missing {}", key);
+        }
+        let blood = values.find("blood").and_then(|v| v.as_array()).unwrap();
+        assert_eq!(blood.len(), 2);
+        assert_eq!(blood[0].find("id").and_then(|v| v.as_u64()), Some(STARLIGHT_ROSE));
+        assert_eq!(blood[0].find("net_value").and_then(|v| v.as_u64()), Some(3420));
+        assert_eq!(blood[0].find("source").and_then(|v| v.as_str()), Some("region"));
+        assert_eq!(blood[1].find("name").and_then(|v| v.as_str()), Some("Aethril"));
+        assert_eq!(blood[1].find("icon").and_then(|v| v.as_str()), Some("inv_misc_questionmark"));
+        assert_eq!(values.find("currencies").and_then(|v| v.find("blood")), Some(&Value::Array(blood.clone())));
+        assert_eq!(values.find("sargerite").and_then(|v| v.as_array()).map(|rows| rows.len()), Some(0));
+
+        let prices = serde_json::to_value(&prices_reply(&state, slug, 61));
+        for key in &["region", "realm", "connected_realm_id", "commodities", "realm_auction_house"] {
+            assert!(prices.find(key).is_some(), "prices reply is missing {}", key);
+        }
+        let commodities = prices.find("commodities").unwrap();
+        assert_eq!(commodities.find("last_update").and_then(|v| v.as_u64()), Some(1509000000000));
+        assert_eq!(commodities.find("prices").and_then(|v| v.find("124105")).and_then(|v| v.as_u64()), Some(1200));
+        assert_eq!(prices.find("realm_auction_house").and_then(|v| v.find("last_update")).and_then(|v| v.as_u64()), Some(0));
+    }
+
+    #[test]
+    fn test_realms_reply() {
+        let state = fixture_region_state();
+        state.commodity_values.write().unwrap().last_update = 1509000000000;
+        state.price_map.get(&61).unwrap().write().unwrap().last_update = 1509000060000;
+
+        let summary = serde_json::to_value(&region_summary(&state, "de_DE"));
+        assert_eq!(summary.find("region").and_then(|v| v.as_str()), Some("us"));
+        assert_eq!(summary.find("commodities_last_update").and_then(|v| v.as_u64()), Some(1509000000000));
+        let connected_realms = summary.find("connected_realms").and_then(|v| v.as_array()).unwrap();
+        assert_eq!(connected_realms.len(), 2);
+        assert_eq!(connected_realms[0].find("id").and_then(|v| v.as_u64()), Some(61));
+        assert_eq!(connected_realms[0].find("last_update").and_then(|v| v.as_u64()), Some(1509000060000));
+        assert_eq!(connected_realms[1].find("last_update").and_then(|v| v.as_u64()), Some(0));
+        let realms = connected_realms[0].find("realms").and_then(|v| v.as_array()).unwrap();
+        assert_eq!(realms[0].find("slug").and_then(|v| v.as_str()), Some("earthen-ring"));
+        assert_eq!(realms[0].find("name").and_then(|v| v.as_str()), Some("Der Irdene Ring"));
+        let slugs: Vec<&str> = connected_realms[1].find("realms").and_then(|v| v.as_array()).unwrap().iter()
+            .map(|realm| realm.find("slug").and_then(|v| v.as_str()).unwrap()).collect();
+        assert_eq!(slugs, vec!["kirin-tor", "sentinels"]);
+    }
+}
@@ -2,198 +2,659 @@
 //! The exposed functionality of this module has been structured
 //! around the particular needs of blood-money: Not all fields are
 //! represented and it's probably not generally useful.
-//! TODO: Add support for locales.
 extern crate hyper;
 extern crate serde_json;
 
 use std::collections::BTreeMap;
-use std::io::Read;
-use std::time::Duration;
+use std::error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use hyper::client::{Client, Response};
-use regex::bytes::Regex;
+use http_transport::{HttpResponse, HttpTransport, HyperTransport};
+use hyper::status::StatusCode;
+use region::Region;
 use serde::de::Deserialize;
 use thread_throttler::ThreadThrottler;
 
-/// The content we care about in the realm status response.
-#[derive(Debug, Serialize, Deserialize)]
+/// Everything that can go wrong while talking to the Blizzard API.
+#[derive(Debug)]
+pub enum ApiError {
+    /// The request never completed: connection failures, timeouts or a truncated body.
+    Transport(String),
+    /// The API answered with a status we don't know how to handle.
+    HttpStatus { status: u16, body: String },
+    /// The response body wasn't the JSON we expected.
+    Decode(String),
+    /// The API has no such realm. Usually a rename or a merge.
+    UnknownRealm(String),
+    /// The API rejected our credentials.
+    Auth(String),
+}
+
+impl ApiError {
+    /// Builds an error from a non-success HTTP reply.
+    fn from_status(status: StatusCode, body: String) -> ApiError {
+        match status {
+            StatusCode::Unauthorized | StatusCode::Forbidden => ApiError::Auth(format!("{}: {}", status, body)),
+            _ => ApiError::HttpStatus { status: status.to_u16(), body: body },
+        }
+    }
+
+    /// Whether trying the same request again could plausibly succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            &ApiError::Transport(_) => true,
+            &ApiError::HttpStatus { status, .. } => status == 429 || status >= 500,
+            &ApiError::Decode(_) | &ApiError::UnknownRealm(_) | &ApiError::Auth(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &ApiError::Transport(ref msg) => write!(f, "transport error: {}", msg),
+            &ApiError::HttpStatus { status, ref body } => write!(f, "HTTP status {}: {}", status, body),
+            &ApiError::Decode(ref msg) => write!(f, "malformed response: {}", msg),
+            &ApiError::UnknownRealm(ref slug) => write!(f, "unknown realm: {}", slug),
+            &ApiError::Auth(ref msg) => write!(f, "authentication failed: {}", msg),
+        }
+    }
+}
+
+impl error::Error for ApiError {
+    fn description(&self) -> &str {
+        match self {
+            &ApiError::Transport(_) => "transport error",
+            &ApiError::HttpStatus { .. } => "unexpected HTTP status",
+            &ApiError::Decode(_) => "malformed response",
+            &ApiError::UnknownRealm(_) => "unknown realm",
+            &ApiError::Auth(_) => "authentication failed",
+        }
+    }
+}
+
+/// How often, and how patiently, we retry failed API calls.
+/// Only errors for which `ApiError::is_retryable()` is true are retried.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first.
+    pub max_attempts: u32,
+    /// Delay before the first retry. Doubles for each subsequent retry.
+    pub initial_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+/// A realm as described by the Game Data API.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RealmInfo {
-    pub name: String,
+    pub id: u64,
+    pub name: String,  // In the client's default locale.
     pub slug: String,
-    pub connected_realms: Vec<String>,
+    #[serde(default)]
+    pub names: BTreeMap<String, String>,  // Keyed by locale.
 }
 
-/// Content we care about in an item info response.
-#[derive(Debug, Deserialize)]
+impl RealmInfo {
+    /// The realm's name in `locale`, or in the default locale if we don't have that one.
+    pub fn localized_name(&self, locale: &str) -> &str {
+        self.names.get(locale).unwrap_or(&self.name)
+    }
+}
+
+/// A group of realms sharing a single auction house.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConnectedRealm {
+    pub id: u64,
+    pub realms: Vec<RealmInfo>,
+}
+
+/// What we keep about an item, from its Game Data item and media replies. Saved item info
+/// still uses the field names of the community API it used to come from.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ItemInfo {
     pub id: u64,
-    pub name: String,
+    pub name: String,  // In the client's default locale.
     pub icon: String,
+    #[serde(default)]
+    pub names: BTreeMap<String, String>,  // Keyed by locale.
+    /// What a vendor pays for one, in copper. None in item info saved before we kept it, as are
+    /// the rest of these.
+    #[serde(rename = "sellPrice", default)]
+    pub sell_price: Option<u64>,
+    #[serde(rename = "itemLevel", default)]
+    pub item_level: Option<u64>,
+    /// 0 for poor through 5 for legendary.
+    #[serde(default)]
+    pub quality: Option<u64>,
+}
+
+impl ItemInfo {
+    /// Whether we have every detail we keep about the item, rather than info saved by an older version.
+    pub fn has_details(&self) -> bool {
+        self.sell_price.is_some() && self.item_level.is_some() && self.quality.is_some()
+    }
+
+    /// The item's name in `locale`, or in the default locale if we don't have that one.
+    pub fn localized_name(&self, locale: &str) -> &str {
+        self.names.get(locale).unwrap_or(&self.name)
+    }
+}
+
+/// A realm as the Game Data API describes it when asked for every locale at once.
+#[derive(Debug, Deserialize)]
+struct GameDataRealm {
+    id: u64,
+    name: BTreeMap<String, String>,
+    slug: String,
+}
+
+/// Represents the reply from a connected realm endpoint when asked for every locale.
+#[derive(Debug, Deserialize)]
+struct GameDataConnectedRealm {
+    id: u64,
+    realms: Vec<GameDataRealm>,
+}
+
+/// A link to another Game Data resource.
+#[derive(Debug, Deserialize)]
+struct Href {
+    href: String,
+}
+
+/// Represents the reply from the connected realm index.
+#[derive(Debug, Deserialize)]
+struct ConnectedRealmIndexReply {
+    connected_realms: Vec<Href>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ItemReference {
+    id: u64,
 }
 
-/// Represents the reply from blizzard's auction data urls.
+/// An item's quality as the Game Data API reports it, e.g. "COMMON".
 #[derive(Debug, Deserialize)]
-struct AuctionListingsReply {
-    realms: Vec<BTreeMap<String, String>>,  // Can't re-use RealmInfo because no connected_realms.
-    auctions: Vec<AuctionListing>,
+struct GameDataQuality {
+    #[serde(rename = "type")]
+    quality_type: String,
 }
 
-/// Represents the JSON reply from the auction data status endpoint.
+impl GameDataQuality {
+    /// The quality as a number, 0 for poor through 5 for legendary and beyond.
+    fn number(&self) -> Option<u64> {
+        match self.quality_type.as_str() {
+            "POOR" => Some(0),
+            "COMMON" => Some(1),
+            "UNCOMMON" => Some(2),
+            "RARE" => Some(3),
+            "EPIC" => Some(4),
+            "LEGENDARY" => Some(5),
+            "ARTIFACT" => Some(6),
+            "HEIRLOOM" => Some(7),
+            _ => None,
+        }
+    }
+}
+
+/// Represents the reply from the item endpoint in a single locale.
 #[derive(Debug, Deserialize)]
-#[allow(non_snake_case)]
-struct AuctionDataPointer {
-    url: String,
-    lastModified: u64,
+struct GameDataItem {
+    id: u64,
+    name: String,
+    #[serde(default)]
+    sell_price: Option<u64>,
+    #[serde(default)]
+    level: Option<u64>,
+    #[serde(default)]
+    quality: Option<GameDataQuality>,
 }
 
+/// One of the files in an item media reply, e.g. its icon.
 #[derive(Debug, Deserialize)]
-struct AuctionDataReply {
-    files: Vec<AuctionDataPointer>, // Will always be 1 element.
+struct MediaAsset {
+    key: String,
+    value: String,  // A URL.
+}
+
+/// Represents the reply from the item media endpoint.
+#[derive(Debug, Deserialize)]
+struct ItemMediaReply {
+    assets: Vec<MediaAsset>,
+}
+
+/// A single auction as the Game Data API reports it. Commodities have a
+/// `unit_price`, everything else has a `buyout` for the whole auction.
+#[derive(Debug, Deserialize)]
+struct GameDataAuction {
+    item: ItemReference,
+    #[serde(default)]
+    buyout: Option<u64>,
+    #[serde(default)]
+    unit_price: Option<u64>,
+    quantity: u64,
+}
+
+/// Represents the reply from a connected realm's auctions endpoint.
+#[derive(Debug, Deserialize)]
+struct AuctionsReply {
+    auctions: Vec<GameDataAuction>,
 }
 
 /// The fields we care about in blizzard's auction reply.
 #[derive(Debug, Deserialize)]
 pub struct AuctionListing {
     pub item: u64,
-    pub buyout: u64,
+    pub buyout: u64,  // For the whole auction, 0 if bid only.
     pub quantity: u64,
 }
 
-#[derive(Clone, Copy, PartialEq)]
-pub enum Region {
-    US,
-    EU,
+impl From<GameDataAuction> for AuctionListing {
+    fn from(auction: GameDataAuction) -> AuctionListing {
+        AuctionListing {
+            item: auction.item.id,
+            buyout: match (auction.buyout, auction.unit_price) {
+                (Some(buyout), _) => buyout,
+                (None, Some(unit_price)) => unit_price * auction.quantity,
+                (None, None) => 0,
+            },
+            quantity: auction.quantity,
+        }
+    }
+}
+
+/// Represents the reply from the OAuth token endpoint.
+#[derive(Debug, Deserialize)]
+struct AccessTokenReply {
+    access_token: String,
+    expires_in: u64,  // Seconds.
 }
 
+/// An OAuth access token and when we should stop using it.
+struct AccessToken {
+    token: String,
+    refresh_at: Instant,
+}
+
+/// Client-credentials issued for an application at https://develop.battle.net/.
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+/// Maximum number of seconds before an access token's expiry that we'll request a new one.
+const TOKEN_REFRESH_MARGIN_SECS: u64 = 300;
+
 pub struct BattleNetApiClient<'a> {
-    pub token: String,
-    client: Client,
-    tt: ThreadThrottler,
+    credentials: Credentials,
+    token_url: String,
+    access_token: Mutex<Option<AccessToken>>,
+    transport: Arc<HttpTransport>,
+    tt: Arc<ThreadThrottler>,
+    retry_policy: RetryPolicy,
     api_host: &'a str,
     api_locale: &'a str,
+    api_namespace: &'a str,
+    api_static_namespace: &'a str,
 }
 
 impl<'a> BattleNetApiClient<'a> {
-    pub fn new(token: &str, region: Region) -> BattleNetApiClient {
-        let mut hyper_client = Client::new();
-        hyper_client.set_read_timeout(Some(Duration::from_secs(300)));
+    /// Creates a client that talks to the live Blizzard API.
+    pub fn new(credentials: Credentials, region: Region) -> BattleNetApiClient<'a> {
+        BattleNetApiClient::with_transport(credentials, region, Arc::new(HyperTransport::new()))
+    }
 
+    /// Creates a client that makes its requests through `transport`.
+    pub fn with_transport(credentials: Credentials, region: Region, transport: Arc<HttpTransport>) -> BattleNetApiClient<'a> {
         BattleNetApiClient {
-            token: token.to_owned(),
-            client: hyper_client,
-            tt: ThreadThrottler::new(100, Duration::new(1, 0)),
-            api_host: match region {
-                Region::US => "us.api.battle.net",
-                Region::EU => "eu.api.battle.net",
-            },
-            api_locale: match region {
-                Region::US => "en_US",
-                Region::EU => "en_GB",
-            },
+            credentials: credentials,
+            token_url: region.info().token_url.to_owned(),
+            access_token: Mutex::new(None),
+            transport: transport,
+            tt: Arc::new(ThreadThrottler::new(100, Duration::new(1, 0))),
+            retry_policy: RetryPolicy::default(),
+            api_host: region.info().api_host,
+            api_locale: region.info().default_locale,
+            api_namespace: region.info().dynamic_namespace,
+            api_static_namespace: region.info().static_namespace,
         }
     }
 
-    /// Try to retrieve something from the Blizzard API. Will retry indefinitely.
-    /// Returns the body as a String.
+    /// Replaces the OAuth token endpoint, e.g. to point at a stub server.
+    pub fn set_token_url(&mut self, token_url: &str) {
+        self.token_url = token_url.to_owned();
+        *self.access_token.lock().unwrap() = None;
+    }
+
+    /// Creates a client for another region using the same credentials and transport.
+    /// Blizzard's rate limits apply per client, so the two share a throttle.
+    pub fn for_region(&self, region: Region) -> BattleNetApiClient<'a> {
+        let mut client = BattleNetApiClient::with_transport(self.credentials.clone(), region, self.transport.clone());
+        client.tt = self.tt.clone();
+        client.retry_policy = self.retry_policy;
+        client
+    }
+
+    /// Replaces the default retry policy.
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.retry_policy = retry_policy;
+    }
+
+    /// Try to retrieve something from the Blizzard API, retrying transient failures
+    /// according to our `RetryPolicy`.
     /// `task` will be used to generate error messages.
-    fn make_blizzard_api_call(&self, url: &str, task: &str) -> String {
-        let mut s = String::new();
-        let mut retries = 0;
+    fn make_blizzard_api_call(&self, url: &str, task: &str) -> Result<HttpResponse, ApiError> {
+        let mut attempt = 1;
+        let mut backoff = self.retry_policy.initial_backoff;
 
         loop {
-            let mut res: Response;
-            retries += 1;
-
-            self.tt.pass_through_or_block();
-            match self.client.get(url).send() {
-                Ok(r) => res = r,
+            match self.try_blizzard_api_call(url) {
+                Ok(s) => return Ok(s),
                 Err(e) => {
-                    println!("Error downloading {}: {}. Retry {}.", task, e, retries);
-                    continue;
+                    if !e.is_retryable() || attempt >= self.retry_policy.max_attempts {
+                        return Err(e);
+                    }
+                    println!("Error downloading {}: {}. Retry {}.", task, e, attempt);
+                    sleep(backoff);
+                    backoff = backoff * 2;
+                    attempt += 1;
                 },
             }
-            // TODO: 404 should really be handled differently here. Maybe make this return a Result<T>?
-            // That would let us account for unrecoverable errors.
-            if res.status != hyper::Ok {
-                println!("Error downloading {}: {}. Retry {}.", task, res.status, retries);
-                continue;
-            }
-            match res.read_to_string(&mut s) {
-                Ok(_) => (),
-                Err(e) => {
-                    println!("Failed to process {}: {}. Retry {}.", task, e, retries);
-                    continue;
-                },
+        }
+    }
+
+    /// A single attempt at an API call.
+    fn try_blizzard_api_call(&self, url: &str) -> Result<HttpResponse, ApiError> {
+        self.tt.pass_through_or_block();
+        let mut res = try!(self.transport.get(url, Some(&try!(self.access_token()))).map_err(ApiError::Transport));
+        if res.status == 401 {
+            // Tokens can be revoked before they expire. Get a fresh one and try once more.
+            *self.access_token.lock().unwrap() = None;
+            self.tt.pass_through_or_block();
+            res = try!(self.transport.get(url, Some(&try!(self.access_token()))).map_err(ApiError::Transport));
+        }
+        if res.status != 200 {
+            return Err(ApiError::from_status(StatusCode::from_u16(res.status), res.body));
+        }
+        Ok(res)
+    }
+
+    /// Returns our cached OAuth access token, requesting a new one via the
+    /// client-credentials flow if we have none or it's about to expire.
+    pub fn access_token(&self) -> Result<String, ApiError> {
+        // Holding the lock while we talk to the token endpoint keeps the other
+        // worker threads from all requesting tokens at once.
+        let mut cached_token = self.access_token.lock().unwrap();
+        if let Some(ref access_token) = *cached_token {
+            if Instant::now() < access_token.refresh_at {
+                return Ok(access_token.token.clone());
             }
-            return s;
         }
+
+        self.tt.pass_through_or_block();
+        let res = try!(self.transport.post_form(&self.token_url, &self.credentials.client_id,
+                                                &self.credentials.client_secret, "grant_type=client_credentials")
+            .map_err(ApiError::Transport));
+        if res.status != 200 {
+            return Err(match ApiError::from_status(StatusCode::from_u16(res.status), res.body) {
+                ApiError::HttpStatus { status: 400, body } => ApiError::Auth(body),
+                e => e,
+            });
+        }
+        let reply: AccessTokenReply = try!(serde_json::from_str(&res.body)
+            .map_err(|e| ApiError::Decode(format!("access token: {}", e))));
+        let margin = if reply.expires_in / 10 < TOKEN_REFRESH_MARGIN_SECS { reply.expires_in / 10 } else { TOKEN_REFRESH_MARGIN_SECS };
+        *cached_token = Some(AccessToken {
+            token: reply.access_token.clone(),
+            refresh_at: Instant::now() + Duration::from_secs(reply.expires_in - margin),
+        });
+        Ok(reply.access_token)
     }
 
-    /// Downloads a list of realms from the Blizzard API.
-    /// Panics if the json response is malformed.
-    pub fn get_realms(&self) -> Vec<RealmInfo> {
-        let mut realm_data: BTreeMap<String, Vec<RealmInfo>> = serde_json::from_str(&self.make_blizzard_api_call(
-            &format!("https://{}/wow/realm/status?locale={}&apikey={}", self.api_host, self.api_locale, self.token), "realm status")
-        ).unwrap();
-        realm_data.remove("realms").expect("Malformed realm response.")
+    /// Downloads the ids of every connected realm in the region.
+    pub fn get_connected_realm_ids(&self) -> Result<Vec<u64>, ApiError> {
+        let reply: ConnectedRealmIndexReply = try!(serde_json::from_str(&try!(self.make_blizzard_api_call(
+            &format!("https://{}/data/wow/connected-realm/index?namespace={}&locale={}", self.api_host, self.api_namespace, self.api_locale),
+            "connected realm index")).body
+        ).map_err(|e| ApiError::Decode(format!("connected realm index: {}", e))));
+        reply.connected_realms.iter().map(|link| {
+            // Links look like https://us.api.blizzard.com/data/wow/connected-realm/11?namespace=dynamic-us
+            link.href.split('?').next().unwrap().rsplit('/').next().unwrap().parse::<u64>()
+                .map_err(|_| ApiError::Decode(format!("connected realm index: bad link {}", link.href)))
+        }).collect()
     }
 
-    /// Downloads the auction listings for the specified realm, or None if the listings haven't
-    /// been updated since `cutoff` or if the json response is illformed.
-    pub fn get_auction_listings(&self, realm_slug: &str, cutoff: u64) -> Option<(u64, Vec<AuctionListing>)> {
-        let mut auction_data_reply: AuctionDataReply;
-        match serde_json::from_str(&self.make_blizzard_api_call(
-            &format!("https://{}/wow/auction/data/{}?locale={}&apikey={}", self.api_host, realm_slug, self.api_locale, self.token),
-            &format!("auction data for {}", realm_slug)))
+    /// Downloads the member realms of a connected realm, with their names in every locale.
+    pub fn get_connected_realm(&self, id: u64) -> Result<ConnectedRealm, ApiError> {
+        // Leaving off the locale gets us every translation in one reply.
+        let body = match self.make_blizzard_api_call(
+            &format!("https://{}/data/wow/connected-realm/{}?namespace={}", self.api_host, id, self.api_namespace),
+            &format!("connected realm {}", id))
         {
-            Ok(reply) => auction_data_reply = reply,
-            Err(e) => {
-                println!("Bad json in auction pointer reply for {}: {}", realm_slug, e);
-                return None;
-            },
+            Err(ApiError::HttpStatus { status: 404, .. }) => return Err(ApiError::UnknownRealm(id.to_string())),
+            r => try!(r).body,
+        };
+        let reply: GameDataConnectedRealm = try!(serde_json::from_str(&body)
+            .map_err(|e| ApiError::Decode(format!("connected realm {}: {}", id, e))));
+        Ok(ConnectedRealm {
+            id: reply.id,
+            realms: reply.realms.into_iter().map(|realm| RealmInfo {
+                id: realm.id,
+                name: realm.name.get(self.api_locale).or(realm.name.values().next()).cloned().unwrap_or(realm.slug.clone()),
+                slug: realm.slug,
+                names: realm.name,
+            }).collect(),
+        })
+    }
+
+    /// Downloads every connected realm in the region along with its member realms.
+    pub fn get_connected_realms(&self) -> Result<Vec<ConnectedRealm>, ApiError> {
+        let ids = try!(self.get_connected_realm_ids());
+        ids.into_iter().map(|id| self.get_connected_realm(id)).collect()
+    }
+
+    /// Downloads the auction listings for the specified connected realm along with their
+    /// modification time, or None if the listings haven't been updated since `cutoff`.
+    /// Times are in milliseconds since the epoch.
+    pub fn get_auction_listings(&self, connected_realm_id: u64, cutoff: u64) -> Result<Option<(u64, Vec<AuctionListing>)>, ApiError> {
+        let res = match self.make_blizzard_api_call(
+            &format!("https://{}/data/wow/connected-realm/{}/auctions?namespace={}&locale={}",
+                     self.api_host, connected_realm_id, self.api_namespace, self.api_locale),
+            &format!("auction listings for connected realm {}", connected_realm_id))
+        {
+            Err(ApiError::HttpStatus { status: 404, .. }) => return Err(ApiError::UnknownRealm(connected_realm_id.to_string())),
+            r => try!(r),
+        };
+        parse_auctions(res, cutoff, &format!("connected realm {}", connected_realm_id))
+    }
+
+    /// Downloads the region-wide commodity auction listings along with their modification
+    /// time, or None if they haven't been updated since `cutoff`.
+    /// Times are in milliseconds since the epoch.
+    pub fn get_commodity_listings(&self, cutoff: u64) -> Result<Option<(u64, Vec<AuctionListing>)>, ApiError> {
+        let res = try!(self.make_blizzard_api_call(
+            &format!("https://{}/data/wow/auctions/commodities?namespace={}&locale={}", self.api_host, self.api_namespace, self.api_locale),
+            "commodity auction listings"));
+        parse_auctions(res, cutoff, "commodities")
+    }
+
+    /// Get info on an item in our default locale, along with its name in each of `locales`.
+    pub fn get_item_info(&self, id: u64, locales: &[String]) -> Result<ItemInfo, ApiError> {
+        let item = try!(self.get_item_in_locale(id, self.api_locale));
+        let mut item_info = ItemInfo {
+            id: item.id,
+            icon: try!(self.get_item_icon(id)),
+            names: BTreeMap::new(),
+            sell_price: item.sell_price,
+            item_level: item.level,
+            quality: item.quality.as_ref().and_then(GameDataQuality::number),
+            name: item.name,
+        };
+        item_info.names.insert(self.api_locale.to_owned(), item_info.name.clone());
+        for locale in locales.iter().filter(|&locale| locale != self.api_locale) {
+            let localized_item = try!(self.get_item_in_locale(id, locale));
+            item_info.names.insert(locale.clone(), localized_item.name);
         }
-        let auction_data_pointer = auction_data_reply.files.pop().unwrap();
-        if auction_data_pointer.lastModified <= cutoff {
-            return None;
-        }
-
-        let mut auction_data_str = self.make_blizzard_api_call(&auction_data_pointer.url, &format!("auction listings for {}", realm_slug));
-        // Auction data strings are especially problematic and often contain numerous invalid bytes in the "owner" and
-        // "ownerRealm" fields. Unfortunately, String::from_utf8_lossy() doesn't appear sufficient to deal with this
-        // so we use the heavy handed approach of a regex to rewrite these fields.
-        // TODO: Make this a lazy_static!.
-        let sanitize_re = Regex::new("\"owner\":\".*?\",\"ownerRealm\":\".*?\",\"bid").unwrap();
-        auction_data_str = String::from_utf8(
-            sanitize_re.replace_all(auction_data_str.as_bytes(),
-                                    &b"\"owner\":\"_\",\"ownerRealm\":\"_\",\"bid"[..])
-            ).unwrap();
-        match serde_json::from_str::<AuctionListingsReply>(&auction_data_str) {
-            Ok(auction_listings_data) => Some((auction_data_pointer.lastModified, auction_listings_data.auctions)),
-            Err(e) => {
-                println!("Error decoding json auction listings for {}: {}", realm_slug, e);
-                None
-            },
+        Ok(item_info)
+    }
+
+    /// Get an item with its name in `locale`.
+    fn get_item_in_locale(&self, id: u64, locale: &str) -> Result<GameDataItem, ApiError> {
+        serde_json::from_str(&try!(self.make_blizzard_api_call(
+            &format!("https://{}/data/wow/item/{}?namespace={}&locale={}", self.api_host, id, self.api_static_namespace, locale),
+            "item info")
+        ).body).map_err(|e| ApiError::Decode(format!("item info for {}: {}", id, e)))
+    }
+
+    /// Get the name of an item's icon, e.g. "inv_herbalism_70_starlightrose", as the render
+    /// hosts name their icon files.
+    fn get_item_icon(&self, id: u64) -> Result<String, ApiError> {
+        let reply: ItemMediaReply = try!(serde_json::from_str(&try!(self.make_blizzard_api_call(
+            &format!("https://{}/data/wow/media/item/{}?namespace={}", self.api_host, id, self.api_static_namespace),
+            "item media")
+        ).body).map_err(|e| ApiError::Decode(format!("item media for {}: {}", id, e))));
+        // Icons look like https://render.worldofwarcraft.com/us/icons/56/inv_herbalism_70_starlightrose.jpg
+        reply.assets.iter().find(|asset| asset.key == "icon")
+            .and_then(|asset| asset.value.rsplit('/').next())
+            .map(|file_name| file_name.split('.').next().unwrap().to_owned())
+            .ok_or(ApiError::Decode(format!("item media for {}: no icon", id)))
+    }
+}
+
+/// Decodes an auctions reply unless it's no newer than `cutoff`.
+/// `source` will be used to generate error messages.
+fn parse_auctions(res: HttpResponse, cutoff: u64, source: &str) -> Result<Option<(u64, Vec<AuctionListing>)>, ApiError> {
+    let last_modified = match res.last_modified {
+        Some(last_modified) => last_modified,
+        None => SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() * 1000,
+    };
+    if last_modified <= cutoff {
+        return Ok(None);
+    }
+    match serde_json::from_str::<AuctionsReply>(&res.body) {
+        Ok(reply) => Ok(Some((last_modified, reply.auctions.into_iter().map(AuctionListing::from).collect()))),
+        Err(e) => Err(ApiError::Decode(format!("auction listings for {}: {}", source, e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use hyper::status::StatusCode;
+
+    use test_support::fixture_client;
+    use super::*;
+
+    fn credentials() -> Credentials {
+        Credentials { client_id: "id".to_owned(), client_secret: "secret".to_owned() }
+    }
+
+    #[test]
+    fn test_retryable_errors() {
+        assert!(ApiError::Transport("connection reset".to_owned()).is_retryable());
+        assert!(ApiError::from_status(StatusCode::ServiceUnavailable, String::new()).is_retryable());
+        assert!(ApiError::from_status(StatusCode::TooManyRequests, String::new()).is_retryable());
+    }
+
+    #[test]
+    fn test_permanent_errors() {
+        match ApiError::from_status(StatusCode::Forbidden, String::new()) {
+            ApiError::Auth(_) => (),
+            e => panic!("Expected an auth error, got {:?}", e),
         }
+        assert!(!ApiError::from_status(StatusCode::NotFound, String::new()).is_retryable());
+        assert!(!ApiError::Decode("bad json".to_owned()).is_retryable());
     }
 
-    /// Helpler function to process a vec of RealmInfo's into vec's of slugs for
-    /// connected realms. Connected realms share an auction house.
-    pub fn process_connected_realms(realm_infos: &Vec<RealmInfo>) -> Vec<Vec<String>> {
-        let mut realm_sets: Vec<Vec<String>> = realm_infos.into_iter().map(|r|
-            r.connected_realms.clone()
-        ).collect();
+    #[test]
+    fn test_get_connected_realms_from_fixture() {
+        let connected_realms = fixture_client().get_connected_realms().unwrap();
+        assert_eq!(connected_realms.len(), 2);
+        assert_eq!(connected_realms[0].id, 61);
+        assert_eq!(connected_realms[0].realms[0].slug, "earthen-ring");
+        assert_eq!(connected_realms[0].realms[0].name, "Earthen Ring");
+        assert_eq!(connected_realms[0].realms[0].localized_name("de_DE"), "Der Irdene Ring");
+        assert_eq!(connected_realms[0].realms[0].localized_name("xx_XX"), "Earthen Ring");
+        let slugs: Vec<&str> = connected_realms[1].realms.iter().map(|r| r.slug.as_str()).collect();
+        assert_eq!(slugs, vec!["kirin-tor", "sentinels"]);
+    }
+
+    #[test]
+    fn test_get_auction_listings_from_fixture() {
+        let client = fixture_client();
+        let (last_modified, listings) = client.get_auction_listings(61, 0).unwrap().unwrap();
+        assert_eq!(listings.len(), 3);
+        assert_eq!(listings[0].item, 124117);
+        assert_eq!(listings[0].buyout, 100000);
+        assert_eq!(listings[2].buyout, 10000000);  // Two at 5000000 each.
+        assert!(client.get_auction_listings(61, last_modified).unwrap().is_none());
+        match client.get_auction_listings(9999, 0) {
+            Err(ApiError::UnknownRealm(_)) => (),
+            r => panic!("Expected an unknown realm error, got {:?}", r),
+        }
+    }
 
-        // This dedup logic relies on the ordering within a connected realms list being the same
-        // for all realms in the list.
-        realm_sets.sort_by(|a, b| a.iter().next().unwrap().cmp(b.iter().next().unwrap()));
-        realm_sets.dedup();
-        return realm_sets;
+    #[test]
+    fn test_get_commodity_listings_from_fixture() {
+        let (_, listings) = fixture_client().get_commodity_listings(0).unwrap().unwrap();
+        assert_eq!(listings.len(), 2);
+        assert_eq!(listings[1].item, 124101);
+        assert_eq!(listings[1].buyout, 3000 * 200);
     }
 
-    /// Get info on an item. Panics on a malformed json response.
-    pub fn get_item_info(&self, id: u64) -> ItemInfo {
-        serde_json::from_str(&self.make_blizzard_api_call(
-            &format!("https://{}/wow/item/{}?locale={}&apikey={}", self.api_host, id, self.api_locale, self.token), "item info")
-        ).unwrap()
+    #[test]
+    fn test_get_item_info_from_fixture() {
+        let item_info = fixture_client().get_item_info(124105, &["de_DE".to_owned()]).unwrap();
+        assert_eq!(item_info.name, "Starlight Rose");
+        assert_eq!(item_info.localized_name("de_DE"), "Sternlichtrose");
+        assert_eq!(item_info.icon, "inv_herbalism_70_starlightrose");
+        assert_eq!(item_info.sell_price, Some(1500));
+        assert_eq!(item_info.item_level, Some(110));
+        assert_eq!(item_info.quality, Some(1));
+        assert!(item_info.has_details());
+    }
+
+    #[test]
+    fn test_access_token_from_stub_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let token_url = format!("http://{}/oauth/token", listener.local_addr().unwrap());
+        // Only answers a single request, so a second token request would fail.
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !String::from_utf8_lossy(&request).contains("client_credentials") {
+                let n = stream.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            let body = "{\"access_token\":\"stub-token\",\"token_type\":\"bearer\",\"expires_in\":86399}";
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body).unwrap();
+            String::from_utf8_lossy(&request).into_owned()
+        });
+
+        let mut client = BattleNetApiClient::new(credentials(), Region::US);
+        client.set_token_url(&token_url);
+        assert_eq!(client.access_token().unwrap(), "stub-token");
+        assert_eq!(client.access_token().unwrap(), "stub-token");
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /oauth/token"));
+        assert!(request.contains("grant_type=client_credentials"));
     }
 }
@@ -0,0 +1,423 @@
+//! The catalog of currencies and the items vendors trade for them. It's read from JSON files
+//! on disk and checked before use, and can be swapped for an edited one while we're running.
+use std::collections::{HashMap, HashSet};
+use std::error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime};
+
+use serde::Deserialize;
+use serde_json;
+
+use battle_net_api_client::ItemInfo;
+use price_estimator::parse_estimator;
+use price_history::AuctionHouse;
+use {Currency, VendorItem, REAGENT};
+
+/// Where the catalog is read from unless told otherwise.
+pub const DEFAULT_CATALOG_DIR: &'static str = "catalog";
+
+const CURRENCIES_FILE: &'static str = "currencies.json";
+const ITEMS_FILE: &'static str = "items.json";
+
+/// Number of seconds between checks for changes to the catalog files.
+const WATCH_PERIOD: u64 = 5;
+
+/// Why a catalog couldn't be used.
+#[derive(Debug)]
+pub enum CatalogError {
+    /// A catalog file couldn't be read.
+    Io(PathBuf, io::Error),
+    /// A catalog file isn't the JSON we expect.
+    Parse(PathBuf, serde_json::Error),
+    /// The catalog was read but doesn't make sense. Holds every problem found.
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for CatalogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &CatalogError::Io(ref path, ref e) => write!(f, "unable to read {}: {}", path.display(), e),
+            &CatalogError::Parse(ref path, ref e) => write!(f, "unable to parse {}: {}", path.display(), e),
+            &CatalogError::Invalid(ref problems) => {
+                try!(write!(f, "invalid catalog:"));
+                for problem in problems {
+                    try!(write!(f, "\n  - {}", problem));
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl error::Error for CatalogError {
+    fn description(&self) -> &str {
+        match self {
+            &CatalogError::Io(..) => "unable to read catalog",
+            &CatalogError::Parse(..) => "malformed catalog",
+            &CatalogError::Invalid(_) => "invalid catalog",
+        }
+    }
+}
+
+/// The icon shown for items we have no info on.
+const MISSING_ICON: &'static str = "inv_misc_questionmark";
+
+/// Everything the catalog defines, along with what we've downloaded about its items.
+#[derive(Debug)]
+pub struct Catalog {
+    /// In the order the realm page shows them.
+    pub currencies: Vec<Currency>,
+    pub items: HashMap<u64, VendorItem>,
+    /// Icons, names and the like for every item.
+    pub item_infos: HashMap<u64, ItemInfo>,
+}
+
+impl Catalog {
+    /// Puts together a catalog from what `read_catalog` returned and info about every item in it.
+    pub fn new(currencies: Vec<Currency>, mut items: HashMap<u64, VendorItem>, item_infos: HashMap<u64, ItemInfo>)
+        -> Catalog {
+        for item in items.values_mut() {
+            item.sell_price = item_infos.get(&item.id).and_then(|info| info.sell_price).unwrap_or(0);
+        }
+        Catalog {
+            currencies: currencies,
+            items: items,
+            item_infos: item_infos,
+        }
+    }
+
+    /// The currency with id `id`, or the first one if there's no such currency.
+    pub fn currency(&self, id: Option<&str>) -> &Currency {
+        id.and_then(|id| self.currencies.iter().find(|currency| currency.id == id)).unwrap_or(&self.currencies[0])
+    }
+
+    /// The name of a catalog item's icon, or a question mark if we have no info on it yet.
+    pub fn item_icon(&self, id: u64) -> String {
+        match self.item_infos.get(&id) {
+            Some(item_info) => item_info.icon.clone(),
+            None => MISSING_ICON.to_owned(),
+        }
+    }
+
+    /// The name of a catalog item in `locale`.
+    pub fn item_name(&self, id: u64, locale: &str) -> String {
+        match self.item_infos.get(&id) {
+            Some(item_info) => item_info.localized_name(locale).to_owned(),
+            None => self.items.get(&id).unwrap().name.clone(),
+        }
+    }
+
+    /// The auction house a catalog item is priced from for a connected realm.
+    pub fn auction_house_for(&self, id: u64, connected_realm_id: u64) -> AuctionHouse {
+        if self.items.get(&id).unwrap().commodity {
+            AuctionHouse::Commodities
+        } else {
+            AuctionHouse::ConnectedRealm(connected_realm_id)
+        }
+    }
+
+    /// Whether anything is priced from realm auction houses rather than the commodity one.
+    pub fn has_realm_items(&self) -> bool {
+        self.items.values().any(|item| !item.commodity)
+    }
+}
+
+/// The catalog in use. Everything using it takes its own reference, so replacing it never
+/// changes the catalog out from under a page or a download in progress.
+pub struct SharedCatalog {
+    current: RwLock<Arc<Catalog>>,
+}
+
+impl SharedCatalog {
+    pub fn new(catalog: Catalog) -> SharedCatalog {
+        SharedCatalog {
+            current: RwLock::new(Arc::new(catalog)),
+        }
+    }
+
+    /// The catalog in use right now.
+    pub fn get(&self) -> Arc<Catalog> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Whether `catalog` is still the one in use, i.e. it hasn't been replaced since it was taken.
+    pub fn is_current(&self, catalog: &Catalog) -> bool {
+        &**self.current.read().unwrap() as *const Catalog == catalog as *const Catalog
+    }
+
+    pub fn replace(&self, catalog: Catalog) {
+        *self.current.write().unwrap() = Arc::new(catalog);
+    }
+}
+
+/// Reads the currencies and items in the catalog in `dir` and checks that they make sense.
+/// Items without an estimator of their own get `default_estimator`.
+pub fn read_catalog(dir: &Path, default_estimator: &str)
+    -> Result<(Vec<Currency>, HashMap<u64, VendorItem>), CatalogError> {
+    let currencies: Vec<Currency> = try!(read_json(&dir.join(CURRENCIES_FILE)));
+    let mut items: Vec<VendorItem> = try!(read_json(&dir.join(ITEMS_FILE)));
+    let mut problems = validate(&currencies, &items);
+    for item in items.iter_mut() {
+        match parse_estimator(item.estimator.as_ref().map(|spec| spec.as_str()).unwrap_or(default_estimator)) {
+            Ok(price_estimator) => item.price_estimator = price_estimator,
+            Err(e) => problems.push(format!("item {} ({}) has a bad estimator: {}", item.id, item.name, e)),
+        }
+    }
+    if !problems.is_empty() {
+        return Err(CatalogError::Invalid(problems));
+    }
+    Ok((currencies, items.into_iter().map(|item| (item.id, item)).collect()))
+}
+
+fn read_json<T: Deserialize>(path: &Path) -> Result<T, CatalogError> {
+    let mut json = String::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_string(&mut json)).map_err(|e| CatalogError::Io(path.to_owned(), e)));
+    serde_json::from_str(&json).map_err(|e| CatalogError::Parse(path.to_owned(), e))
+}
+
+/// Everything wrong with a catalog, as messages naming the currency or item at fault.
+fn validate(currencies: &[Currency], items: &[VendorItem]) -> Vec<String> {
+    let mut problems = Vec::new();
+    if currencies.is_empty() {
+        problems.push(format!("{} has no currencies", CURRENCIES_FILE));
+    }
+    let mut currency_ids = HashSet::new();
+    for currency in currencies {
+        if currency.id == REAGENT {
+            problems.push(format!("currency id \"{}\" is reserved for items that are only mats", REAGENT));
+        }
+        if !currency_ids.insert(currency.id.as_str()) {
+            problems.push(format!("currency \"{}\" is defined more than once", currency.id));
+        }
+    }
+    let mut item_ids = HashSet::new();
+    for item in items {
+        if !item_ids.insert(item.id) {
+            problems.push(format!("item {} ({}) is defined more than once", item.id, item.name));
+        }
+    }
+    for item in items {
+        if item.quantity == 0 {
+            problems.push(format!("item {} ({}) has a quantity of 0", item.id, item.name));
+        }
+        if item.cost == 0 {
+            problems.push(format!("item {} ({}) has a cost of 0", item.id, item.name));
+        }
+        let recipe_mats = item.recipes.iter().flat_map(|recipe| recipe.mats.iter());
+        for mat in item.mats.iter().flat_map(|mats| mats.iter()).chain(recipe_mats) {
+            if !item_ids.contains(&mat.id) {
+                problems.push(format!("item {} ({}) needs mat {}, which isn't in the catalog", item.id, item.name, mat.id));
+            }
+            if mat.quantity == 0 {
+                problems.push(format!("item {} ({}) needs 0 of mat {}", item.id, item.name, mat.id));
+            }
+        }
+        if item.recipes.iter().any(|recipe| recipe.yields == 0) {
+            problems.push(format!("item {} ({}) has a recipe that yields 0", item.id, item.name));
+        }
+    }
+    problems
+}
+
+/// Things in a valid catalog that are probably mistakes: currencies whose section on the
+/// realm page would be empty, and reagents nothing needs.
+pub fn lint(currencies: &[Currency], items: &HashMap<u64, VendorItem>) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for currency in currencies {
+        if !items.values().any(|item| item.vendor_type == currency.id) {
+            warnings.push(format!("currency \"{}\" has no items, so its section will be empty", currency.id));
+        }
+    }
+    let used_mats: HashSet<u64> = items.values()
+        .flat_map(|item| {
+            let recipe_mats = item.recipes.iter().flat_map(|recipe| recipe.mats.iter());
+            item.mats.iter().flat_map(|mats| mats.iter()).chain(recipe_mats).map(|mat| mat.id)
+        })
+        .collect();
+    let mut ids: Vec<&u64> = items.keys().collect();
+    ids.sort();
+    for id in ids {
+        let item = items.get(id).unwrap();
+        if item.vendor_type == REAGENT && !used_mats.contains(id) {
+            warnings.push(format!("item {} ({}) is a reagent that nothing needs", item.id, item.name));
+        }
+    }
+    warnings
+}
+
+/// Items in the catalog in `dir` sold for a currency that isn't one of `currencies`, so the
+/// realm page has no section for them. Each message says where the item starts in items.json.
+pub fn unsectioned_items(dir: &Path, currencies: &[Currency]) -> Result<Vec<String>, CatalogError> {
+    let path = dir.join(ITEMS_FILE);
+    let mut json = String::new();
+    try!(File::open(&path).and_then(|mut f| f.read_to_string(&mut json)).map_err(|e| CatalogError::Io(path.clone(), e)));
+    let items: Vec<VendorItem> = try!(serde_json::from_str(&json).map_err(|e| CatalogError::Parse(path.clone(), e)));
+    let positions = item_positions(&json);
+    Ok(items.iter().zip(positions)
+        .filter(|&(item, _)| item.vendor_type != REAGENT && !currencies.iter().any(|currency| currency.id == item.vendor_type))
+        .map(|(item, (line, column))| {
+            format!("{} line {} column {}: item {} ({}) is sold for \"{}\", which has no section",
+                    ITEMS_FILE, line, column, item.id, item.name, item.vendor_type)
+        })
+        .collect())
+}
+
+/// The line and column, counting from 1, where each object in the top level array of `json`
+/// starts. Only meaningful for JSON that parses.
+fn item_positions(json: &str) -> Vec<(usize, usize)> {
+    let mut positions = Vec::new();
+    let (mut line, mut column) = (1, 0);
+    let mut depth = 0;
+    let (mut in_string, mut escaped) = (false, false);
+    for c in json.chars() {
+        if c == '\n' {
+            line += 1;
+            column = 0;
+            continue;
+        }
+        column += 1;
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '[' | '{' => {
+                if c == '{' && depth == 1 {
+                    positions.push((line, column));
+                }
+                depth += 1;
+            },
+            ']' | '}' => depth -= 1,
+            _ => {},
+        }
+    }
+    positions
+}
+
+/// When each catalog file in `dir` was last modified, or None if we can't tell.
+fn modified_times(dir: &Path) -> Vec<Option<SystemTime>> {
+    [CURRENCIES_FILE, ITEMS_FILE].iter()
+        .map(|name| fs::metadata(dir.join(name)).and_then(|metadata| metadata.modified()).ok())
+        .collect()
+}
+
+/// Calls `on_change` every time a catalog file in `dir` changes, checking every few seconds.
+/// Never returns.
+pub fn watch<F: FnMut()>(dir: &Path, mut on_change: F) {
+    let mut last_modified = modified_times(dir);
+    loop {
+        sleep(Duration::from_secs(WATCH_PERIOD));
+        let modified = modified_times(dir);
+        if modified != last_modified {
+            last_modified = modified;
+            on_change();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use price_estimator::DEFAULT_ESTIMATOR;
+    use test_support::TempDir;
+    use super::*;
+
+    fn write_catalog(name: &str, currencies: &str, items: &str) -> TempDir {
+        let dir = TempDir::new(name);
+        File::create(dir.path().join(CURRENCIES_FILE)).unwrap().write_all(currencies.as_bytes()).unwrap();
+        File::create(dir.path().join(ITEMS_FILE)).unwrap().write_all(items.as_bytes()).unwrap();
+        dir
+    }
+
+    const CURRENCIES: &'static str = r#"[{"id": "blood", "name": "Blood of Sargeras", "icon": "blood", "description": ""}]"#;
+
+    #[test]
+    fn test_read_catalog() {
+        let dir = write_catalog("blood-money-catalog-test", CURRENCIES, r#"[
+            {"id": 1, "name": "Gem", "quantity": 1, "cost": 10, "vendor_type": "blood", "mats": [{"id": 2, "quantity": 2}]},
+            {"id": 2, "name": "Flux", "quantity": 1, "vendor_type": "reagent", "estimator": "weighted-median"}
+        ]"#);
+        let (currencies, items) = read_catalog(dir.path(), DEFAULT_ESTIMATOR).unwrap();
+        assert_eq!(currencies[0].id, "blood");
+        assert_eq!(items.get(&1).unwrap().cost, 10);
+        assert_eq!(items.get(&2).unwrap().price_estimator.describe(), parse_estimator("weighted-median").unwrap().describe());
+    }
+
+    #[test]
+    fn test_invalid_catalogs() {
+        let dir = write_catalog("blood-money-invalid-catalog-test", CURRENCIES, r#"[
+            {"id": 1, "name": "Gem", "quantity": 0, "vendor_type": "blood", "mats": [{"id": 3, "quantity": 1}]},
+            {"id": 1, "name": "Other Gem", "quantity": 1, "vendor_type": "sargerite"},
+            {"id": 2, "name": "Flux", "quantity": 1, "vendor_type": "reagent", "estimator": "median-ish"}
+        ]"#);
+        match read_catalog(dir.path(), DEFAULT_ESTIMATOR) {
+            Err(CatalogError::Invalid(problems)) => assert_eq!(problems.len(), 4, "{:?}", problems),
+            other => panic!("Expected an invalid catalog, got {:?}", other.map(|_| ())),
+        }
+
+        let dir = write_catalog("blood-money-unparsable-catalog-test", CURRENCIES, "[{");
+        let error = read_catalog(dir.path(), DEFAULT_ESTIMATOR).err().expect("Expected a parse error");
+        match error {
+            CatalogError::Parse(ref path, _) => assert_eq!(path, &dir.path().join(ITEMS_FILE)),
+            ref other => panic!("Expected a parse error, got {:?}", other),
+        }
+        let message = format!("{}", error);
+        assert!(message.contains("line 1 column 2"), "{}", message);
+    }
+
+    #[test]
+    fn test_lint() {
+        let currencies = r#"[{"id": "blood", "name": "Blood of Sargeras", "icon": "blood", "description": ""},
+                             {"id": "sargerite", "name": "Primal Sargerite", "icon": "sargerite", "description": ""}]"#;
+        let dir = write_catalog("blood-money-lint-catalog-test", currencies, r#"[
+            {"id": 1, "name": "Gem", "quantity": 1, "vendor_type": "blood", "mats": [{"id": 2, "quantity": 2}]},
+            {"id": 2, "name": "Flux", "quantity": 1, "vendor_type": "reagent"},
+            {"id": 3, "name": "Ore", "quantity": 1, "vendor_type": "reagent"}
+        ]"#);
+        let (currencies, items) = read_catalog(dir.path(), DEFAULT_ESTIMATOR).unwrap();
+        assert_eq!(lint(&currencies, &items), vec![
+            "currency \"sargerite\" has no items, so its section will be empty".to_owned(),
+            "item 3 (Ore) is a reagent that nothing needs".to_owned(),
+        ]);
+    }
+
+    #[test]
+    fn test_unsectioned_items() {
+        let dir = write_catalog("blood-money-unsectioned-catalog-test", CURRENCIES, r#"[
+            {"id": 1, "name": "Gem \"{[", "quantity": 1, "vendor_type": "blood"},
+            {"id": 2, "name": "Other Gem", "quantity": 1, "vendor_type": "sargerite",
+             "recipes": [{"yield": 1, "mats": [{"id": 1, "quantity": 1}]}]},
+              {"id": 3, "name": "Flux", "quantity": 1, "vendor_type": "reagent"}, {"id": 4, "name": "Ore", "quantity": 1, "vendor_type": "ore"}
+        ]"#);
+        let (currencies, _) = read_catalog(dir.path(), DEFAULT_ESTIMATOR).unwrap();
+        assert_eq!(unsectioned_items(dir.path(), &currencies).unwrap(), vec![
+            "items.json line 3 column 13: item 2 (Other Gem) is sold for \"sargerite\", which has no section".to_owned(),
+            "items.json line 5 column 83: item 4 (Ore) is sold for \"ore\", which has no section".to_owned(),
+        ]);
+    }
+
+    #[test]
+    fn test_shipped_catalog_is_valid() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(DEFAULT_CATALOG_DIR);
+        match read_catalog(&dir, DEFAULT_ESTIMATOR) {
+            Ok((currencies, items)) => {
+                assert_eq!(lint(&currencies, &items), Vec::<String>::new());
+                assert_eq!(unsectioned_items(&dir, &currencies).unwrap(), Vec::<String>::new());
+            },
+            Err(e) => panic!("{}", e),
+        }
+    }
+}
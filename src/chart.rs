@@ -0,0 +1,130 @@
+//! Server-rendered SVG charts of item values, so pages work without any JavaScript.
+use std::cmp;
+
+/// Formats an amount of copper the way the game does, e.g. "12g34s56c".
+pub fn format_money(copper: u64) -> String {
+    format!("{}g{}s{}c", copper / 10_000, copper / 100 % 100, copper % 100)
+}
+
+/// Formats a timestamp in milliseconds since the epoch as "YYYY-MM-DD HH:MM" UTC.
+pub fn format_timestamp(timestamp: u64) -> String {
+    let seconds = timestamp / 1000;
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, seconds % 86_400 / 3600, seconds % 3600 / 60)
+}
+
+/// Converts days since 1970-01-01 to a (year, month, day) date.
+/// See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = (if z >= 0 { z } else { z - 146_096 }) / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Maps (timestamp, value) points onto a `width` by `height` box with its top left at (`left`, `top`).
+/// Returns None if there's nothing worth drawing.
+fn scale_points(points: &[(u64, u64)], left: f64, top: f64, width: f64, height: f64) -> Option<Vec<(f64, f64)>> {
+    if points.len() < 2 {
+        return None;
+    }
+    let (min_time, max_time) = (points[0].0, points[points.len() - 1].0);
+    let min_value = points.iter().map(|p| p.1).min().unwrap();
+    let max_value = points.iter().map(|p| p.1).max().unwrap();
+    let time_span = cmp::max(max_time - min_time, 1) as f64;
+    let value_span = cmp::max(max_value - min_value, 1) as f64;
+    Some(points.iter().map(|&(time, value)| {
+        (left + (time - min_time) as f64 / time_span * width,
+         top + height - (value - min_value) as f64 / value_span * height)
+    }).collect())
+}
+
+fn polyline(points: &[(f64, f64)]) -> String {
+    points.iter().map(|&(x, y)| format!("{:.1},{:.1}", x, y)).collect::<Vec<_>>().join(" ")
+}
+
+/// A tiny line chart with no axes, for showing a trend next to a value.
+/// Points are (timestamp, value), oldest first. Empty if there aren't enough points to draw a line.
+pub fn sparkline(points: &[(u64, u64)], width: u32, height: u32) -> String {
+    match scale_points(points, 1.0, 1.0, width as f64 - 2.0, height as f64 - 2.0) {
+        Some(scaled) => format!(
+            "<svg class=\"sparkline\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\" xmlns=\"http://www.w3.org/2000/svg\">\
+             <polyline fill=\"none\" stroke=\"#99d1ce\" stroke-width=\"1.5\" points=\"{points}\"/></svg>",
+            w = width, h = height, points = polyline(&scaled)),
+        None => String::new(),
+    }
+}
+
+/// A full size line chart of values in copper with labelled axes. Every point has a
+/// tooltip with its exact time and value. Points are (timestamp, value), oldest first.
+/// Empty if there aren't enough points to draw a line.
+pub fn line_chart(points: &[(u64, u64)], width: u32, height: u32) -> String {
+    const LEFT_MARGIN: f64 = 90.0;
+    const BOTTOM_MARGIN: f64 = 30.0;
+    const TOP_MARGIN: f64 = 10.0;
+    const RIGHT_MARGIN: f64 = 10.0;
+    const GRID_LINES: u64 = 4;
+    let plot_width = width as f64 - LEFT_MARGIN - RIGHT_MARGIN;
+    let plot_height = height as f64 - TOP_MARGIN - BOTTOM_MARGIN;
+    let scaled = match scale_points(points, LEFT_MARGIN, TOP_MARGIN, plot_width, plot_height) {
+        Some(scaled) => scaled,
+        None => return String::new(),
+    };
+    let min_value = points.iter().map(|p| p.1).min().unwrap();
+    let max_value = points.iter().map(|p| p.1).max().unwrap();
+
+    let mut svg = format!(
+        "<svg class=\"chart\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\" xmlns=\"http://www.w3.org/2000/svg\">\
+         <style>circle {{ fill: #66023C; }} circle:hover {{ fill: #99d1ce; r: 6; }} text {{ fill: gray; font-size: 11px; }}</style>",
+        w = width, h = height);
+    // Horizontal grid lines labelled with their value.
+    for i in 0..(GRID_LINES + 1) {
+        let y = TOP_MARGIN + plot_height * i as f64 / GRID_LINES as f64;
+        let value = max_value - (max_value - min_value) * i / GRID_LINES;
+        svg.push_str(&format!(
+            "<line x1=\"{x1}\" y1=\"{y:.1}\" x2=\"{x2}\" y2=\"{y:.1}\" stroke=\"#333\"/>\
+             <text x=\"{tx}\" y=\"{ty:.1}\" text-anchor=\"end\">{label}</text>",
+            x1 = LEFT_MARGIN, x2 = LEFT_MARGIN + plot_width, y = y, tx = LEFT_MARGIN - 5.0, ty = y + 4.0,
+            label = format_money(value)));
+    }
+    // The time range along the bottom.
+    let label_y = height as f64 - 10.0;
+    svg.push_str(&format!(
+        "<text x=\"{x1}\" y=\"{y}\">{start}</text><text x=\"{x2}\" y=\"{y}\" text-anchor=\"end\">{end}</text>",
+        x1 = LEFT_MARGIN, x2 = LEFT_MARGIN + plot_width, y = label_y,
+        start = format_timestamp(points[0].0), end = format_timestamp(points[points.len() - 1].0)));
+    svg.push_str(&format!(
+        "<polyline fill=\"none\" stroke=\"#99d1ce\" stroke-width=\"2\" points=\"{}\"/>", polyline(&scaled)));
+    for (&(x, y), &(timestamp, value)) in scaled.iter().zip(points.iter()) {
+        svg.push_str(&format!("<circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"3\"><title>{}: {}</title></circle>",
+                              x, y, format_timestamp(timestamp), format_money(value)));
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formatting() {
+        assert_eq!(format_money(123456), "12g34s56c");
+        assert_eq!(format_money(5), "0g0s5c");
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00");
+        assert_eq!(format_timestamp(1509000000000), "2017-10-26 06:40");
+    }
+
+    #[test]
+    fn test_sparkline() {
+        assert_eq!(sparkline(&[(0, 100)], 100, 20), "");
+        let svg = sparkline(&[(0, 100), (10, 300), (20, 200)], 102, 22);
+        assert!(svg.contains("points=\"1.0,21.0 51.0,1.0 101.0,11.0\""), "{}", svg);
+    }
+}
@@ -0,0 +1,258 @@
+//! Settings for running blood-money. They're read from a TOML config file, then from
+//! `BLOOD_MONEY_<SETTING>` environment variables, then from `--<setting>=<value>` flags,
+//! each overriding the last.
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use toml;
+
+use battle_net_api_client::Credentials;
+use catalog::DEFAULT_CATALOG_DIR;
+use fees::{parse_cut_percent, parse_duration_hours, parse_expected_relists, FeeModel};
+use locale::is_supported;
+use price_estimator::{parse_estimator, DEFAULT_ESTIMATOR};
+use realm_reader::{DEFAULT_FETCH_PERIOD, DEFAULT_FETCH_THREADS};
+use region::Region;
+
+/// The config file read if there is one and no other is asked for.
+pub const DEFAULT_CONFIG_FILE: &'static str = "blood-money.toml";
+
+/// Environment variables starting with this set the setting named by the rest, e.g.
+/// `BLOOD_MONEY_DATA_DIR` sets `data_dir`.
+const ENV_PREFIX: &'static str = "BLOOD_MONEY_";
+
+/// Everything that can be set in the config file, the environment or on the command line.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub credentials: Credentials,
+    pub regions: Vec<Region>,
+    /// Locales to offer besides each region's default one.
+    pub locales: Vec<String>,
+    /// Addresses to serve the site on, e.g. "localhost:3000".
+    pub bind: Vec<String>,
+    pub data_dir: String,
+    /// Where `fetch-once` writes what it fetched.
+    pub output_dir: String,
+    pub catalog_dir: String,
+    /// How to price items that don't say. See `parse_estimator`.
+    pub estimator: String,
+    pub fees: FeeModel,
+    /// Number of threads per region fetching connected realms' auctions at once.
+    pub fetch_threads: u32,
+    /// Number of seconds between the starts of fetches.
+    pub fetch_period: u64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            credentials: Credentials {
+                client_id: String::new(),
+                client_secret: String::new(),
+            },
+            regions: Vec::new(),
+            locales: Vec::new(),
+            bind: vec!["localhost:3000".to_owned()],
+            data_dir: "data".to_owned(),
+            output_dir: "output".to_owned(),
+            catalog_dir: DEFAULT_CATALOG_DIR.to_owned(),
+            estimator: DEFAULT_ESTIMATOR.to_owned(),
+            fees: FeeModel::default(),
+            fetch_threads: DEFAULT_FETCH_THREADS,
+            fetch_period: DEFAULT_FETCH_PERIOD,
+        }
+    }
+}
+
+/// Splits a comma separated list, ignoring empty entries.
+fn parse_list(value: &str) -> Vec<String> {
+    value.split(',').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()).map(|entry| entry.to_owned()).collect()
+}
+
+fn parse_positive(value: &str, what: &str) -> Result<u64, String> {
+    match value.parse::<u64>() {
+        Ok(number) if number > 0 => Ok(number),
+        _ => Err(format!("{} must be a whole number above 0, not {}", what, value)),
+    }
+}
+
+/// A TOML value as the text `Config::set` takes, or None for tables and nested lists.
+fn setting_text(value: &toml::Value) -> Option<String> {
+    match *value {
+        toml::Value::String(ref text) => Some(text.clone()),
+        toml::Value::Integer(number) => Some(number.to_string()),
+        toml::Value::Float(number) => Some(number.to_string()),
+        toml::Value::Boolean(flag) => Some(flag.to_string()),
+        toml::Value::Array(ref values) => {
+            let entries: Option<Vec<String>> = values.iter().map(|value| match *value {
+                toml::Value::Array(_) | toml::Value::Table(_) => None,
+                ref value => setting_text(value),
+            }).collect();
+            entries.map(|entries| entries.join(","))
+        },
+        toml::Value::Datetime(_) | toml::Value::Table(_) => None,
+    }
+}
+
+impl Config {
+    /// Changes a setting, given its value as text. Lists are comma separated.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "client_id" => self.credentials.client_id = value.to_owned(),
+            "client_secret" => self.credentials.client_secret = value.to_owned(),
+            "regions" => {
+                let mut regions = Vec::new();
+                for name in parse_list(value) {
+                    match Region::from_name(&name) {
+                        Some(region) => if !regions.contains(&region) {
+                            regions.push(region);
+                        },
+                        None => return Err(format!("Unknown region {}", name)),
+                    }
+                }
+                self.regions = regions;
+            },
+            "locales" => {
+                let locales = parse_list(value);
+                if let Some(locale) = locales.iter().find(|locale| !is_supported(locale)) {
+                    return Err(format!("Unknown locale {}", locale));
+                }
+                self.locales = locales;
+            },
+            "bind" => {
+                let bind = parse_list(value);
+                if bind.is_empty() {
+                    return Err("There must be at least one address to bind to".to_owned());
+                }
+                self.bind = bind;
+            },
+            "data_dir" => self.data_dir = value.to_owned(),
+            "output_dir" => self.output_dir = value.to_owned(),
+            "catalog_dir" => self.catalog_dir = value.to_owned(),
+            "estimator" => {
+                try!(parse_estimator(value));
+                self.estimator = value.to_owned();
+            },
+            "ah_cut" => self.fees.cut_percent = try!(parse_cut_percent(value)),
+            "auction_duration" => self.fees.duration_hours = try!(parse_duration_hours(value)),
+            "relists" => self.fees.expected_relists = try!(parse_expected_relists(value)),
+            "fetch_threads" => {
+                let threads = try!(parse_positive(value, "Fetch threads"));
+                if threads > u32::max_value() as u64 {
+                    return Err(format!("Fetch threads must be at most {}, not {}", u32::max_value(), value));
+                }
+                self.fetch_threads = threads as u32;
+            },
+            "fetch_period" => self.fetch_period = try!(parse_positive(value, "Fetch period")),
+            _ => return Err(format!("Unknown setting {}", key)),
+        }
+        Ok(())
+    }
+
+    /// Applies the settings in the text of a TOML config file. `name` is used in error messages.
+    pub fn apply_toml(&mut self, name: &str, text: &str) -> Result<(), String> {
+        let mut parser = toml::Parser::new(text);
+        let table = match parser.parse() {
+            Some(table) => table,
+            None => {
+                let error = &parser.errors[0];
+                let (line, column) = parser.to_linecol(error.lo);
+                return Err(format!("{}:{}:{}: {}", name, line + 1, column + 1, error.desc));
+            }
+        };
+        for (key, value) in &table {
+            let text = try!(setting_text(value).ok_or(format!("{}: {} must be a string, number or list", name, key)));
+            try!(self.set(key, &text).map_err(|e| format!("{}: {}", name, e)));
+        }
+        Ok(())
+    }
+
+    /// Applies the settings in the config file at `path`.
+    pub fn apply_file(&mut self, path: &Path) -> io::Result<Result<(), String>> {
+        let mut text = String::new();
+        try!(File::open(path).and_then(|mut f| f.read_to_string(&mut text)));
+        Ok(self.apply_toml(&path.display().to_string(), &text))
+    }
+
+    /// Applies the settings in `BLOOD_MONEY_<SETTING>` environment variables, given every
+    /// variable as a (name, value) pair. `BLOOD_MONEY_CONFIG` names the config file rather
+    /// than a setting, so it's skipped.
+    pub fn apply_env<I: Iterator<Item = (String, String)>>(&mut self, vars: I) -> Result<(), String> {
+        for (name, value) in vars {
+            if !name.starts_with(ENV_PREFIX) || name == config_env_var() {
+                continue;
+            }
+            let key = name[ENV_PREFIX.len()..].to_lowercase();
+            try!(self.set(&key, &value).map_err(|e| format!("{}: {}", name, e)));
+        }
+        Ok(())
+    }
+
+    /// Locales to offer: the configured ones plus every region's default, sorted.
+    pub fn all_locales(&self) -> Vec<String> {
+        let mut locales = self.locales.clone();
+        for region in &self.regions {
+            locales.push(region.info().default_locale.to_owned());
+        }
+        locales.sort();
+        locales.dedup();
+        locales
+    }
+}
+
+/// The environment variable naming the config file to read.
+pub fn config_env_var() -> String {
+    format!("{}CONFIG", ENV_PREFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|&(name, value)| (name.to_owned(), value.to_owned())).collect()
+    }
+
+    #[test]
+    fn test_toml_then_env_then_flags() {
+        let mut config = Config::default();
+        config.apply_toml("test.toml", r#"
+            client_id = "id"
+            client_secret = "secret"
+            regions = ["us", "eu"]
+            bind = ["0.0.0.0:3000", "[::]:3000"]
+            ah_cut = 10
+            relists = 0.5
+            fetch_threads = 2
+        "#).unwrap();
+        assert_eq!(config.credentials.client_secret, "secret");
+        assert_eq!(config.regions, vec![Region::US, Region::EU]);
+        assert_eq!(config.bind, vec!["0.0.0.0:3000".to_owned(), "[::]:3000".to_owned()]);
+        assert_eq!(config.fees, FeeModel { cut_percent: 10, duration_hours: 48, expected_relists: 0.5 });
+        assert_eq!(config.fetch_threads, 2);
+        assert_eq!(config.data_dir, "data");
+
+        config.apply_env(vars(&[("BLOOD_MONEY_DATA_DIR", "/var/lib/blood-money"), ("BLOOD_MONEY_CONFIG", "other.toml"),
+                                ("BLOOD_MONEY_REGIONS", "kr"), ("HOME", "/root")]).into_iter()).unwrap();
+        assert_eq!(config.data_dir, "/var/lib/blood-money");
+        assert_eq!(config.regions, vec![Region::KR]);
+
+        config.set("fetch_period", "600").unwrap();
+        assert_eq!(config.fetch_period, 600);
+        assert_eq!(config.all_locales(), vec!["ko_KR".to_owned()]);
+    }
+
+    #[test]
+    fn test_bad_settings() {
+        let mut config = Config::default();
+        assert_eq!(config.apply_toml("test.toml", "regions = [\"us\"]\nah_cut = ").unwrap_err().find("test.toml:2:"), Some(0));
+        assert!(config.apply_toml("test.toml", "ah_cut = 100").is_err());
+        assert!(config.apply_toml("test.toml", "[server]\nbind = \"localhost:80\"").is_err());
+        assert!(config.set("regions", "us,atlantis").is_err());
+        assert!(config.set("fetch_threads", "0").is_err());
+        assert!(config.set("estimator", "median-ish").is_err());
+        assert!(config.set("colour", "red").is_err());
+        assert!(config.apply_env(vars(&[("BLOOD_MONEY_LOCALES", "xx_XX")]).into_iter()).is_err());
+    }
+}
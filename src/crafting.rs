@@ -0,0 +1,135 @@
+//! Works out the cheapest way to get hold of crafting mats: buying them on the auction house,
+//! buying them from an NPC, or crafting them from their own mats, as deep as recipes go.
+use std::cmp;
+use std::collections::HashMap;
+
+use VendorItem;
+
+/// Where a mat comes from in the cheapest plan for it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    AuctionHouse,
+    Vendor,
+    /// Crafted by a recipe that makes `yields` at a time from (quantity, plan) mats.
+    Craft { yields: u64, mats: Vec<(u64, Plan)> },
+}
+
+/// The cheapest way we found to get one of an item.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Plan {
+    pub id: u64,
+    /// In copper.
+    pub unit_cost: u64,
+    pub source: Source,
+}
+
+/// Keeps whichever of two plans is cheaper, preferring the one we already had on a tie.
+fn cheaper(best: Option<Plan>, plan: Plan) -> Option<Plan> {
+    match best {
+        Some(best) if best.unit_cost <= plan.unit_cost => Some(best),
+        _ => Some(plan),
+    }
+}
+
+/// Finds the cheapest way to get one of item `id`, or None if it can't be bought or crafted.
+/// `visiting` holds the items being crafted further up the tree; crafting any of those again
+/// would be a cycle, so their recipes are skipped.
+fn cheapest_plan(id: u64, items: &HashMap<u64, VendorItem>, unit_prices: &HashMap<u64, u64>,
+                 visiting: &mut Vec<u64>) -> Option<Plan> {
+    let mut best = unit_prices.get(&id).map(|&price| Plan { id: id, unit_cost: price, source: Source::AuctionHouse });
+    let item = match items.get(&id) {
+        Some(item) => item,
+        None => return best,
+    };
+    if let Some(price) = item.vendor_price {
+        best = cheaper(best, Plan { id: id, unit_cost: price, source: Source::Vendor });
+    }
+    if visiting.contains(&id) {
+        return best;
+    }
+    visiting.push(id);
+    for recipe in &item.recipes {
+        let mats: Option<Vec<(u64, Plan)>> = recipe.mats.iter()
+            .map(|mat| cheapest_plan(mat.id, items, unit_prices, visiting).map(|plan| (mat.quantity, plan)))
+            .collect();
+        if let Some(mats) = mats {
+            let total: u64 = mats.iter().map(|&(quantity, ref plan)| quantity * plan.unit_cost).sum();
+            let yields = cmp::max(recipe.yields, 1);
+            best = cheaper(best, Plan { id: id, unit_cost: total / yields, source: Source::Craft { yields: yields, mats: mats } });
+        }
+    }
+    visiting.pop();
+    best
+}
+
+/// The cheapest plan for each of the mats a vendor item needs besides currency, as
+/// (mat id, quantity, plan). Mats we can't price have no plan.
+pub fn mat_plans(item: &VendorItem, items: &HashMap<u64, VendorItem>,
+                 unit_prices: &HashMap<u64, u64>) -> Vec<(u64, u64, Option<Plan>)> {
+    let mut visiting = vec![item.id];
+    item.mats.iter().flat_map(|mats_list| mats_list.iter())
+        .map(|mat| (mat.id, mat.quantity, cheapest_plan(mat.id, items, unit_prices, &mut visiting)))
+        .collect()
+}
+
+/// What the mats for one of a vendor item cost, each bought or crafted as cheaply as possible.
+/// Mats we can't price count as free.
+pub fn mat_cost(item: &VendorItem, items: &HashMap<u64, VendorItem>, unit_prices: &HashMap<u64, u64>) -> u64 {
+    mat_plans(item, items, unit_prices).iter()
+        .map(|&(_, quantity, ref plan)| quantity * plan.as_ref().map(|plan| plan.unit_cost).unwrap_or(0))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use super::*;
+
+    fn items(json: &str) -> HashMap<u64, VendorItem> {
+        let items: Vec<VendorItem> = serde_json::from_str(json).unwrap();
+        items.into_iter().map(|item| (item.id, item)).collect()
+    }
+
+    const CATALOG: &'static str = r#"[
+        {"id": 1, "name": "Gem", "quantity": 1, "vendor_type": "blood", "mats": [{"id": 2, "quantity": 2}]},
+        {"id": 2, "name": "Flux", "quantity": 1, "vendor_type": "reagent",
+         "recipes": [{"mats": [{"id": 3, "quantity": 3}], "yield": 2},
+                     {"mats": [{"id": 4, "quantity": 1}]}]},
+        {"id": 3, "name": "Ore", "quantity": 1, "vendor_type": "reagent", "vendor_price": 100},
+        {"id": 4, "name": "Ingot", "quantity": 1, "vendor_type": "reagent",
+         "recipes": [{"mats": [{"id": 2, "quantity": 1}]}]}
+    ]"#;
+
+    #[test]
+    fn test_cheapest_of_auction_house_vendor_and_crafting() {
+        let items = items(CATALOG);
+        // Flux isn't listed, so it's crafted from 3 vendor Ore making 2 at a time. The Ingot
+        // recipe would need Flux itself, so it's skipped as a cycle.
+        let plans = mat_plans(items.get(&1).unwrap(), &items, &HashMap::new());
+        assert_eq!(plans.len(), 1);
+        let plan = plans[0].2.as_ref().unwrap();
+        assert_eq!(plan.unit_cost, 150);
+        match plan.source {
+            Source::Craft { yields: 2, ref mats } => assert_eq!(mats[0].1.source, Source::Vendor),
+            ref source => panic!("Expected a craft, got {:?}", source),
+        }
+        assert_eq!(mat_cost(items.get(&1).unwrap(), &items, &HashMap::new()), 300);
+
+        // Cheap enough Flux on the auction house wins, and cheap Ingots can be crafted into it.
+        let prices: HashMap<u64, u64> = vec![(2, 120), (4, 50)].into_iter().collect();
+        let plans = mat_plans(items.get(&1).unwrap(), &items, &prices);
+        assert_eq!(plans[0].2.as_ref().unwrap().unit_cost, 50);
+        let prices: HashMap<u64, u64> = vec![(2, 120)].into_iter().collect();
+        assert_eq!(mat_plans(items.get(&1).unwrap(), &items, &prices)[0].2.as_ref().unwrap().source, Source::AuctionHouse);
+    }
+
+    #[test]
+    fn test_unpriceable_mats() {
+        let items = items(r#"[
+            {"id": 1, "name": "Gem", "quantity": 1, "vendor_type": "blood", "mats": [{"id": 5, "quantity": 1}]}
+        ]"#);
+        assert_eq!(mat_plans(items.get(&1).unwrap(), &items, &HashMap::new())[0].2, None);
+        assert_eq!(mat_cost(items.get(&1).unwrap(), &items, &HashMap::new()), 0);
+    }
+}
@@ -0,0 +1,211 @@
+//! CSV and TSV exports of realm values, for pasting into spreadsheets.
+use iron::headers::{Charset, ContentDisposition, DispositionParam, DispositionType};
+use iron::mime::Mime;
+use iron::prelude::*;
+use iron::status;
+
+use catalog::Catalog;
+use chart::format_timestamp;
+use realm_reader::RegionState;
+use {CurrentRealmValues, ItemValue, Site};
+
+/// A delimited text format we can export in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Tsv,
+}
+
+impl Format {
+    /// Splits a file name like "earthen-ring.csv" into its stem and format.
+    pub fn from_file_name(file_name: &str) -> Option<(&str, Format)> {
+        if file_name.ends_with(".csv") {
+            Some((&file_name[..file_name.len() - 4], Format::Csv))
+        } else if file_name.ends_with(".tsv") {
+            Some((&file_name[..file_name.len() - 4], Format::Tsv))
+        } else {
+            None
+        }
+    }
+
+    /// Looks up a format by its file extension, e.g. "csv".
+    pub fn from_extension(extension: &str) -> Option<Format> {
+        match extension {
+            "csv" => Some(Format::Csv),
+            "tsv" => Some(Format::Tsv),
+            _ => None,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match *self {
+            Format::Csv => "csv",
+            Format::Tsv => "tsv",
+        }
+    }
+
+    fn mime(&self) -> Mime {
+        match *self {
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Tsv => "text/tab-separated-values; charset=utf-8",
+        }.parse().unwrap()
+    }
+
+    /// Appends a row to `out`, quoting or cleaning up fields as the format needs.
+    fn write_row<S: AsRef<str>>(&self, out: &mut String, fields: &[S]) {
+        for (i, field) in fields.iter().enumerate() {
+            let field = field.as_ref();
+            match *self {
+                Format::Csv => {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    if field.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+                        out.push('"');
+                        out.push_str(&field.replace('"', "\"\""));
+                        out.push('"');
+                    } else {
+                        out.push_str(field);
+                    }
+                },
+                Format::Tsv => {
+                    if i > 0 {
+                        out.push('\t');
+                    }
+                    // TSV has no quoting, so whitespace that would break the row becomes a space.
+                    out.push_str(&field.replace(|c: char| c == '\t' || c == '\n' || c == '\r', " "));
+                },
+            }
+        }
+        out.push_str("\r\n");
+    }
+}
+
+const HEADER: [&'static str; 19] = [
+    "region", "realm", "connected_realm_id", "item_id", "item_name", "vendor_type", "source",
+    "price", "estimator", "vendor_sell_price", "value_per_currency", "net_value_per_currency", "vendor_it",
+    "quantity_listed", "listings", "discarded_listings", "discarded_quantity", "last_update", "last_update_utc",
+];
+
+/// Adds a row for every catalog item to `out` for one realm, whose connected realm's values are `realm_prices`.
+fn write_realm_rows(out: &mut String, format: Format, catalog: &Catalog, state: &RegionState, slug: &str,
+                    realm_prices: &CurrentRealmValues, commodity_prices: &CurrentRealmValues, locale: &str) {
+    let connected_realm_id = *state.realm_aliases.get(slug).unwrap();
+    let mut item_ids: Vec<&u64> = catalog.items.keys().collect();
+    item_ids.sort();
+    for &id in item_ids {
+        let item = catalog.items.get(&id).unwrap();
+        let (values, source) = if item.commodity { (commodity_prices, "region") } else { (realm_prices, "realm") };
+        let (value, net_value, vendor_it) = match values.item_values.values().flat_map(|item_values| item_values.iter())
+            .find(|item_value| item_value.id == id) {
+            Some(&ItemValue { value, net_value, vendor_it, .. }) =>
+                (value.to_string(), net_value.to_string(), vendor_it.to_string()),
+            None => (String::new(), String::new(), String::new()),
+        };
+        let (quantity, listings, discarded_listings, discarded_quantity) = match values.markets.get(&id) {
+            Some(market) => (market.quantity.to_string(), market.listings.to_string(),
+                             market.discarded_listings.to_string(), market.discarded_quantity.to_string()),
+            None => (String::new(), String::new(), String::new(), String::new()),
+        };
+        let (last_update, last_update_utc) = match values.last_update {
+            0 => (String::new(), String::new()),
+            last_update => (last_update.to_string(), format_timestamp(last_update)),
+        };
+        format.write_row(out, &[
+            state.region.info().name.to_owned(),
+            slug.to_owned(),
+            connected_realm_id.to_string(),
+            id.to_string(),
+            catalog.item_name(id, locale),
+            item.vendor_type.clone(),
+            source.to_owned(),
+            values.value_map.get(&id).map(|price| price.to_string()).unwrap_or(String::new()),
+            item.price_estimator.describe(),
+            item.sell_price.to_string(),
+            value,
+            net_value,
+            vendor_it,
+            quantity,
+            listings,
+            discarded_listings,
+            discarded_quantity,
+            last_update,
+            last_update_utc,
+        ]);
+    }
+}
+
+fn response(format: Format, file_name: String, body: String) -> IronResult<Response> {
+    let mut response = Response::with((format.mime(), status::Ok, body));
+    response.headers.set(ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(Charset::Ext("UTF-8".to_owned()), None, file_name.into_bytes())],
+    });
+    Ok(response)
+}
+
+/// One row per catalog item for a realm, with a header.
+pub fn realm_rows(catalog: &Catalog, state: &RegionState, slug: &str, format: Format, locale: &str) -> String {
+    let realm_prices = state.realm_values(slug).unwrap().read().unwrap();
+    let commodity_prices = state.commodity_values.read().unwrap();
+    let mut out = String::new();
+    format.write_row(&mut out, &HEADER);
+    write_realm_rows(&mut out, format, catalog, state, slug, &realm_prices, &commodity_prices, locale);
+    out
+}
+
+/// One row per realm and catalog item for a whole region, with a header.
+pub fn region_rows(catalog: &Catalog, state: &RegionState, format: Format, locale: &str) -> String {
+    let commodity_prices = state.commodity_values.read().unwrap();
+    let mut out = String::new();
+    format.write_row(&mut out, &HEADER);
+    for realm in &state.realms {
+        let realm_prices = state.realm_values(&realm.slug).unwrap().read().unwrap();
+        write_realm_rows(&mut out, format, catalog, state, &realm.slug, &realm_prices, &commodity_prices, locale);
+    }
+    out
+}
+
+/// What to call an export of a realm, e.g. "us-earthen-ring.csv".
+pub fn realm_file_name(state: &RegionState, slug: &str, format: Format) -> String {
+    format!("{}-{}.{}", state.region, slug, format.extension())
+}
+
+/// What to call an export of a whole region, e.g. "us-export.csv".
+pub fn region_file_name(state: &RegionState, format: Format) -> String {
+    format!("{}-export.{}", state.region, format.extension())
+}
+
+/// Exports one row per catalog item for a realm.
+pub fn export_realm(site: &Site, state: &RegionState, slug: &str, format: Format, locale: &str) -> IronResult<Response> {
+    let out = realm_rows(&site.catalog(), state, slug, format, locale);
+    response(format, realm_file_name(state, slug, format), out)
+}
+
+/// Exports one row per realm and catalog item for a whole region.
+pub fn export_region(site: &Site, state: &RegionState, format: Format, locale: &str) -> IronResult<Response> {
+    let out = region_rows(&site.catalog(), state, format, locale);
+    response(format, region_file_name(state, format), out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_names() {
+        assert_eq!(Format::from_file_name("earthen-ring.csv"), Some(("earthen-ring", Format::Csv)));
+        assert_eq!(Format::from_file_name("earthen-ring.tsv"), Some(("earthen-ring", Format::Tsv)));
+        assert_eq!(Format::from_file_name("earthen-ring"), None);
+    }
+
+    #[test]
+    fn test_write_row() {
+        let mut csv = String::new();
+        Format::Csv.write_row(&mut csv, &["Kil'jaeden", "Shal'dorei Silk, bolt", "say \"hi\""]);
+        assert_eq!(csv, "Kil'jaeden,\"Shal'dorei Silk, bolt\",\"say \"\"hi\"\"\"\r\n");
+        let mut tsv = String::new();
+        Format::Tsv.write_row(&mut tsv, &["a\tb", "c"]);
+        assert_eq!(tsv, "a b\tc\r\n");
+    }
+}
@@ -0,0 +1,106 @@
+//! The auction house's cut and deposits, which turn what an auction sells for into what
+//! the seller actually ends up with.
+
+/// The deposit on an auction is this percentage of what a vendor pays for the item, for every
+/// 12 hours it's posted. It's kept if the auction expires and refunded if it sells.
+const DEPOSIT_PERCENT_PER_12_HOURS: u64 = 15;
+
+/// Auction durations, in hours, that the auction house offers.
+pub const AUCTION_DURATIONS: [u64; 3] = [12, 24, 48];
+
+/// What selling on the auction house costs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeeModel {
+    /// Percentage of the sale price the auction house keeps.
+    pub cut_percent: u64,
+    /// How long auctions are posted for, in hours.
+    pub duration_hours: u64,
+    /// How many times an auction is expected to expire unsold before it sells. Every expiry
+    /// loses the deposit.
+    pub expected_relists: f64,
+}
+
+impl Default for FeeModel {
+    fn default() -> FeeModel {
+        FeeModel {
+            cut_percent: 5,
+            duration_hours: 48,
+            expected_relists: 1.0,
+        }
+    }
+}
+
+impl FeeModel {
+    /// The deposit on one unit of an item a vendor pays `sell_price` copper for.
+    pub fn deposit(&self, sell_price: u64) -> u64 {
+        sell_price * DEPOSIT_PERCENT_PER_12_HOURS * (self.duration_hours / 12) / 100
+    }
+
+    /// The deposits a seller expects to lose on one unit to auctions expiring before it sells.
+    pub fn expected_deposit_loss(&self, sell_price: u64) -> f64 {
+        self.deposit(sell_price) as f64 * self.expected_relists
+    }
+
+    /// What a seller expects to keep of one unit that sells for `price`, after the cut and
+    /// the deposits lost to expected relists.
+    pub fn net_unit_price(&self, price: u64, sell_price: u64) -> u64 {
+        let after_cut = price * (100 - self.cut_percent) / 100;
+        after_cut.saturating_sub(self.expected_deposit_loss(sell_price) as u64)
+    }
+
+    /// A short summary, for showing next to net values.
+    pub fn describe(&self) -> String {
+        format!("{}% cut, {}h deposits x{}", self.cut_percent, self.duration_hours, self.expected_relists)
+    }
+}
+
+/// Parses an auction house cut percentage.
+pub fn parse_cut_percent(value: &str) -> Result<u64, String> {
+    match value.parse::<u64>() {
+        Ok(percent) if percent < 100 => Ok(percent),
+        _ => Err(format!("AH cut must be a percentage below 100, not {}", value)),
+    }
+}
+
+/// Parses an auction duration in hours.
+pub fn parse_duration_hours(value: &str) -> Result<u64, String> {
+    match value.parse::<u64>() {
+        Ok(hours) if AUCTION_DURATIONS.contains(&hours) => Ok(hours),
+        _ => Err(format!("Auction duration must be 12, 24 or 48 hours, not {}", value)),
+    }
+}
+
+/// Parses how many times auctions are expected to be relisted.
+pub fn parse_expected_relists(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(relists) if relists >= 0.0 && relists.is_finite() => Ok(relists),
+        _ => Err(format!("Expected relists must be a number of at least 0, not {}", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_net_unit_price() {
+        let fees = FeeModel::default();
+        // A 48 hour deposit is 60% of the vendor price.
+        assert_eq!(fees.deposit(1000), 600);
+        assert_eq!(fees.net_unit_price(100000, 1000), 95000 - 600);
+        let fees = FeeModel { cut_percent: 5, duration_hours: 12, expected_relists: 0.5 };
+        assert_eq!(fees.net_unit_price(100000, 1000), 95000 - 75);
+        // Deposits can eat the whole sale.
+        assert_eq!(fees.net_unit_price(10, 1000), 0);
+    }
+
+    #[test]
+    fn test_parsing() {
+        assert_eq!(parse_cut_percent("5"), Ok(5));
+        assert!(parse_cut_percent("100").is_err());
+        assert_eq!(parse_duration_hours("24"), Ok(24));
+        assert!(parse_duration_hours("36").is_err());
+        assert_eq!(parse_expected_relists("1.5"), Ok(1.5));
+        assert!(parse_expected_relists("-1").is_err());
+    }
+}
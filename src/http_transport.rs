@@ -0,0 +1,150 @@
+//! The HTTP layer underneath `BattleNetApiClient`.
+//! Abstracting it lets us drive the client from recorded responses
+//! instead of the live Blizzard API.
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::PathBuf;
+use std::time::{Duration, UNIX_EPOCH};
+
+use hyper::Result as HyperResult;
+use hyper::client::{Client, Response};
+use hyper::header::{Authorization, Basic, Bearer, ContentType, LastModified};
+
+/// A completed HTTP exchange. Non-success statuses are still responses;
+/// only failures to get a reply at all are errors.
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: String,
+    /// The Last-Modified header, in milliseconds since the epoch.
+    pub last_modified: Option<u64>,
+}
+
+/// Something that can perform HTTP requests for the API client.
+pub trait HttpTransport: Send + Sync {
+    /// Fetches `url`, sending `bearer_token` if present.
+    /// Returns a description of the failure if no reply was received.
+    fn get(&self, url: &str, bearer_token: Option<&str>) -> Result<HttpResponse, String>;
+
+    /// POSTs a form encoded `body` to `url` using HTTP basic authentication.
+    fn post_form(&self, url: &str, username: &str, password: &str, body: &str) -> Result<HttpResponse, String>;
+}
+
+/// The real thing: talks to the network with hyper.
+pub struct HyperTransport {
+    client: Client,
+}
+
+impl HyperTransport {
+    pub fn new() -> HyperTransport {
+        let mut client = Client::new();
+        client.set_read_timeout(Some(Duration::from_secs(300)));
+        HyperTransport { client: client }
+    }
+}
+
+impl HttpTransport for HyperTransport {
+    fn get(&self, url: &str, bearer_token: Option<&str>) -> Result<HttpResponse, String> {
+        let mut req = self.client.get(url);
+        if let Some(token) = bearer_token {
+            req = req.header(Authorization(Bearer { token: token.to_owned() }));
+        }
+        read_hyper_response(req.send())
+    }
+
+    fn post_form(&self, url: &str, username: &str, password: &str, body: &str) -> Result<HttpResponse, String> {
+        read_hyper_response(self.client.post(url)
+            .header(Authorization(Basic { username: username.to_owned(), password: Some(password.to_owned()) }))
+            .header(ContentType::form_url_encoded())
+            .body(body)
+            .send())
+    }
+}
+
+/// Converts the result of sending a hyper request into an `HttpResponse`.
+fn read_hyper_response(result: HyperResult<Response>) -> Result<HttpResponse, String> {
+    let mut res = try!(result.map_err(|e| e.to_string()));
+    let mut body = String::new();
+    let read_result = res.read_to_string(&mut body);
+    if res.status.is_success() {
+        try!(read_result.map_err(|e| e.to_string()));
+    }
+    Ok(HttpResponse {
+        status: res.status.to_u16(),
+        body: body,
+        last_modified: res.headers.get::<LastModified>().map(|&LastModified(ref date)| date.0.to_timespec().sec as u64 * 1000),
+    })
+}
+
+/// Serves responses from a directory of recorded JSON files.
+/// Each URL maps to a file named by `FixtureTransport::file_name_for_url`,
+/// whose modification time is reported as the Last-Modified time.
+/// URLs without a recording get a 404.
+pub struct FixtureTransport {
+    dir: PathBuf,
+}
+
+impl FixtureTransport {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> FixtureTransport {
+        FixtureTransport { dir: dir.into() }
+    }
+
+    /// The name of the fixture file for a URL: the scheme and any access token are dropped,
+    /// and separators are flattened to underscores. For example
+    /// `https://us.api.blizzard.com/data/wow/connected-realm/index?namespace=dynamic-us` is served from
+    /// `us.api.blizzard.com_data_wow_connected-realm_index_namespace=dynamic-us.json`.
+    pub fn file_name_for_url(url: &str) -> String {
+        let without_scheme = match url.find("://") {
+            Some(i) => &url[i + 3..],
+            None => url,
+        };
+        let mut parts = without_scheme.splitn(2, '?');
+        let path = parts.next().unwrap();
+        let path = if path.ends_with(".json") { &path[..path.len() - 5] } else { path };
+        let mut name = path.trim_right_matches('/').replace('/', "_");
+        if let Some(query) = parts.next() {
+            for param in query.split('&').filter(|p| !p.starts_with("access_token=")) {
+                name.push('_');
+                name.push_str(param);
+            }
+        }
+        name.push_str(".json");
+        name
+    }
+}
+
+impl HttpTransport for FixtureTransport {
+    fn get(&self, url: &str, _: Option<&str>) -> Result<HttpResponse, String> {
+        let path = self.dir.join(FixtureTransport::file_name_for_url(url));
+        let mut body = String::new();
+        match File::open(&path) {
+            Ok(mut f) => {
+                try!(f.read_to_string(&mut body).map_err(|e| format!("{}: {}", path.display(), e)));
+                let last_modified = f.metadata().and_then(|m| m.modified()).ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs() * 1000);
+                Ok(HttpResponse { status: 200, body: body, last_modified: last_modified })
+            },
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(HttpResponse { status: 404, body: body, last_modified: None }),
+            Err(e) => Err(format!("{}: {}", path.display(), e)),
+        }
+    }
+
+    fn post_form(&self, url: &str, _: &str, _: &str, _: &str) -> Result<HttpResponse, String> {
+        self.get(url, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixture_file_names() {
+        assert_eq!(FixtureTransport::file_name_for_url(
+                       "https://us.api.blizzard.com/data/wow/connected-realm/index?namespace=dynamic-us&access_token=abc"),
+                   "us.api.blizzard.com_data_wow_connected-realm_index_namespace=dynamic-us.json");
+        assert_eq!(FixtureTransport::file_name_for_url("http://auction-api-us.worldofwarcraft.com/auction-data/abc/auctions.json"),
+                   "auction-api-us.worldofwarcraft.com_auction-data_abc_auctions.json");
+    }
+}
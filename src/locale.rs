@@ -0,0 +1,381 @@
+//! Locales for item names, realm names and the fixed text of the web UI.
+use iron::headers::AcceptLanguage;
+
+/// Every locale the Blizzard API can name things in.
+pub const SUPPORTED_LOCALES: [&'static str; 12] = [
+    "en_US", "en_GB", "es_MX", "es_ES", "pt_BR", "de_DE", "fr_FR", "it_IT", "ru_RU", "ko_KR", "zh_TW", "zh_CN",
+];
+
+/// Whether `locale` is one Blizzard knows about.
+pub fn is_supported(locale: &str) -> bool {
+    SUPPORTED_LOCALES.contains(&locale)
+}
+
+/// Picks the locale to render a page in. An explicit `locale` query parameter wins,
+/// then the best match from the Accept-Language header, then `default`.
+/// Only locales in `available` are ever chosen, unless `default` isn't among them.
+pub fn negotiate_locale(query: Option<&str>, accept_language: Option<&AcceptLanguage>,
+                        available: &[String], default: &str) -> String {
+    if let Some(query) = query {
+        for param in query.split('&') {
+            if param.starts_with("locale=") {
+                let requested = param["locale=".len()..].replace('-', "_");
+                if available.contains(&requested) {
+                    return requested;
+                }
+            }
+        }
+    }
+    if let Some(&AcceptLanguage(ref languages)) = accept_language {
+        let mut languages = languages.clone();
+        languages.sort_by(|a, b| b.quality.cmp(&a.quality));
+        for tag in languages.iter().filter(|tag| tag.quality.0 > 0).map(|tag| &tag.item) {
+            let language = match tag.language {
+                Some(ref language) => language.to_lowercase(),
+                None => continue,
+            };
+            // Prefer an exact language and region match, then any locale for the language.
+            if let Some(ref region) = tag.region {
+                let locale = format!("{}_{}", language, region.to_uppercase());
+                if available.contains(&locale) {
+                    return locale;
+                }
+            }
+            if default.starts_with(&format!("{}_", language)) {
+                return default.to_owned();
+            }
+            if let Some(locale) = available.iter().find(|l| l.starts_with(&format!("{}_", language))) {
+                return locale.clone();
+            }
+        }
+    }
+    default.to_owned()
+}
+
+/// The fixed text on our pages.
+#[derive(Debug, Serialize)]
+pub struct UiStrings {
+    pub realm_list: &'static str,
+    pub select_realm: &'static str,
+    pub pick_region: &'static str,
+    pub current_values_for: &'static str,
+    pub still_waiting: &'static str,
+    pub last_updated: &'static str,
+    pub minute_ago: &'static str,
+    pub minutes_ago: &'static str,
+    pub best_investment: &'static str,
+    pub crafting_mats: &'static str,
+    pub region_source: &'static str,
+    pub realm_source: &'static str,
+    pub region_source_title: &'static str,
+    pub realm_source_title: &'static str,
+    pub language: &'static str,
+    pub value_history: &'static str,
+    pub days: &'static str,
+    pub all_time: &'static str,
+    pub not_enough_history: &'static str,
+    pub back_to_realm: &'static str,
+    pub latest: &'static str,
+    pub lowest: &'static str,
+    pub highest: &'static str,
+    pub based_on: &'static str,
+    pub listings: &'static str,
+    pub units: &'static str,
+    pub ignored_as_outliers: &'static str,
+    pub optimizer: &'static str,
+    pub optimizer_explanation: &'static str,
+    pub currency_to_spend: &'static str,
+    pub optimize: &'static str,
+    pub spend: &'static str,
+    pub expected_value: &'static str,
+    pub last_one_worth: &'static str,
+    pub worth_of_the: &'static str,
+    pub at_listed_values: &'static str,
+    pub nothing_worth_selling: &'static str,
+    pub net: &'static str,
+    pub vendor_it: &'static str,
+    pub vendor_pays: &'static str,
+    pub from_auction_house: &'static str,
+    pub from_vendor: &'static str,
+    pub crafted_makes: &'static str,
+    pub no_price: &'static str,
+    pub for_currency: &'static str,
+}
+
+static ENGLISH: UiStrings = UiStrings {
+    realm_list: "Realm List",
+    select_realm: "Select your realm from the listings below to view current values.",
+    pick_region: "Pick another region",
+    current_values_for: "Current values for",
+    still_waiting: "Still waiting on results for this realm.",
+    last_updated: "Last updated",
+    minute_ago: "minute ago",
+    minutes_ago: "minutes ago",
+    best_investment: "Best investment is currently",
+    crafting_mats: "Crafting Mats",
+    region_source: "region",
+    realm_source: "realm",
+    region_source_title: "Priced from the region-wide commodity auction house",
+    realm_source_title: "Priced from this realm's auction house",
+    language: "Language",
+    value_history: "Value history",
+    days: "days",
+    all_time: "All",
+    not_enough_history: "Not enough history to draw a chart yet.",
+    back_to_realm: "Back to realm",
+    latest: "Latest",
+    lowest: "Lowest",
+    highest: "Highest",
+    based_on: "based on",
+    listings: "listings",
+    units: "units",
+    ignored_as_outliers: "ignored as outliers",
+    optimizer: "Spending optimizer",
+    optimizer_explanation: "Selling lots of one item floods its market, so each extra unit of currency spent on it is worth less than the last. This splits your currency across items to get the most out of it, assuming you undercut the listings you compete with.",
+    currency_to_spend: "Currency to spend",
+    optimize: "Optimize",
+    spend: "Spend",
+    expected_value: "Expected value",
+    last_one_worth: "Last one worth",
+    worth_of_the: "Worth of currency number",
+    at_listed_values: "At listed values",
+    nothing_worth_selling: "Nothing is worth buying with this currency right now.",
+    net: "net",
+    vendor_it: "Vendor it",
+    vendor_pays: "A vendor pays",
+    from_auction_house: "auction house",
+    from_vendor: "vendor",
+    crafted_makes: "crafted, makes",
+    no_price: "no price",
+    for_currency: "for",
+};
+
+static GERMAN: UiStrings = UiStrings {
+    realm_list: "Realmliste",
+    select_realm: "Wähle deinen Realm aus der Liste, um die aktuellen Werte zu sehen.",
+    pick_region: "Andere Region wählen",
+    current_values_for: "Aktuelle Werte für",
+    still_waiting: "Für diesen Realm liegen noch keine Ergebnisse vor.",
+    last_updated: "Zuletzt aktualisiert vor",
+    minute_ago: "Minute",
+    minutes_ago: "Minuten",
+    best_investment: "Die beste Investition ist derzeit",
+    crafting_mats: "Handwerksmaterialien",
+    region_source: "Region",
+    realm_source: "Realm",
+    region_source_title: "Preis aus dem regionsweiten Rohstoff-Auktionshaus",
+    realm_source_title: "Preis aus dem Auktionshaus dieses Realms",
+    language: "Sprache",
+    value_history: "Wertverlauf",
+    days: "Tage",
+    all_time: "Alles",
+    not_enough_history: "Noch nicht genug Daten für ein Diagramm.",
+    back_to_realm: "Zurück zum Realm",
+    latest: "Aktuell",
+    lowest: "Niedrigster",
+    highest: "Höchster",
+    based_on: "basiert auf",
+    listings: "Angeboten",
+    units: "Einheiten",
+    ignored_as_outliers: "als Ausreißer ignoriert",
+    optimizer: "Ausgabenoptimierer",
+    optimizer_explanation: "Wer viel von einem Gegenstand verkauft, überschwemmt seinen Markt, daher ist jede weitere dafür ausgegebene Währung weniger wert als die letzte. Dies verteilt deine Währung so auf die Gegenstände, dass du am meisten herausholst, wenn du die konkurrierenden Angebote unterbietest.",
+    currency_to_spend: "Auszugebende Währung",
+    optimize: "Optimieren",
+    spend: "Ausgeben",
+    expected_value: "Erwarteter Wert",
+    last_one_worth: "Letzte wert",
+    worth_of_the: "Wert von Währung Nummer",
+    at_listed_values: "Zu angezeigten Werten",
+    nothing_worth_selling: "Derzeit lohnt sich nichts mit dieser Währung.",
+    net: "netto",
+    vendor_it: "An Händler verkaufen",
+    vendor_pays: "Ein Händler zahlt",
+    from_auction_house: "Auktionshaus",
+    from_vendor: "Händler",
+    crafted_makes: "hergestellt, ergibt",
+    no_price: "kein Preis",
+    for_currency: "für",
+};
+
+static FRENCH: UiStrings = UiStrings {
+    realm_list: "Liste des royaumes",
+    select_realm: "Choisissez votre royaume ci-dessous pour voir les valeurs actuelles.",
+    pick_region: "Choisir une autre région",
+    current_values_for: "Valeurs actuelles pour",
+    still_waiting: "Résultats pas encore disponibles pour ce royaume.",
+    last_updated: "Mis à jour il y a",
+    minute_ago: "minute",
+    minutes_ago: "minutes",
+    best_investment: "Le meilleur investissement est actuellement",
+    crafting_mats: "Composants d'artisanat",
+    region_source: "région",
+    realm_source: "royaume",
+    region_source_title: "Prix de l'hôtel des ventes des marchandises de la région",
+    realm_source_title: "Prix de l'hôtel des ventes de ce royaume",
+    language: "Langue",
+    value_history: "Historique de la valeur",
+    days: "jours",
+    all_time: "Tout",
+    not_enough_history: "Pas encore assez d'historique pour un graphique.",
+    back_to_realm: "Retour au royaume",
+    latest: "Dernière",
+    lowest: "Plus basse",
+    highest: "Plus haute",
+    based_on: "basé sur",
+    listings: "annonces",
+    units: "unités",
+    ignored_as_outliers: "ignorées comme aberrantes",
+    optimizer: "Optimiseur de dépenses",
+    optimizer_explanation: "Vendre beaucoup d'un même objet inonde son marché : chaque unité de monnaie supplémentaire dépensée pour lui rapporte moins que la précédente. Ceci répartit votre monnaie entre les objets pour en tirer le maximum, en supposant que vous cassez les prix des annonces concurrentes.",
+    currency_to_spend: "Monnaie à dépenser",
+    optimize: "Optimiser",
+    spend: "Dépenser",
+    expected_value: "Valeur attendue",
+    last_one_worth: "Dernière vaut",
+    worth_of_the: "Valeur de l'unité numéro",
+    at_listed_values: "Aux valeurs affichées",
+    nothing_worth_selling: "Rien ne vaut la peine d'être acheté avec cette monnaie pour l'instant.",
+    net: "net",
+    vendor_it: "Vendez-le au marchand",
+    vendor_pays: "Un marchand paie",
+    from_auction_house: "hôtel des ventes",
+    from_vendor: "marchand",
+    crafted_makes: "fabriqué, donne",
+    no_price: "pas de prix",
+    for_currency: "pour",
+};
+
+static SPANISH: UiStrings = UiStrings {
+    realm_list: "Lista de reinos",
+    select_realm: "Elige tu reino de la lista para ver los valores actuales.",
+    pick_region: "Elegir otra región",
+    current_values_for: "Valores actuales para",
+    still_waiting: "Todavía esperando resultados para este reino.",
+    last_updated: "Actualizado hace",
+    minute_ago: "minuto",
+    minutes_ago: "minutos",
+    best_investment: "La mejor inversión actualmente es",
+    crafting_mats: "Materiales de fabricación",
+    region_source: "región",
+    realm_source: "reino",
+    region_source_title: "Precio de la casa de subastas de mercancías de la región",
+    realm_source_title: "Precio de la casa de subastas de este reino",
+    language: "Idioma",
+    value_history: "Historial de valor",
+    days: "días",
+    all_time: "Todo",
+    not_enough_history: "Aún no hay historial suficiente para un gráfico.",
+    back_to_realm: "Volver al reino",
+    latest: "Último",
+    lowest: "Mínimo",
+    highest: "Máximo",
+    based_on: "basado en",
+    listings: "subastas",
+    units: "unidades",
+    ignored_as_outliers: "ignoradas como atípicas",
+    optimizer: "Optimizador de gastos",
+    optimizer_explanation: "Vender mucho de un mismo objeto inunda su mercado, así que cada unidad de moneda adicional gastada en él vale menos que la anterior. Esto reparte tu moneda entre los objetos para sacarle el máximo, suponiendo que rebajas las subastas con las que compites.",
+    currency_to_spend: "Moneda a gastar",
+    optimize: "Optimizar",
+    spend: "Gastar",
+    expected_value: "Valor esperado",
+    last_one_worth: "La última vale",
+    worth_of_the: "Valor de la unidad número",
+    at_listed_values: "A los valores mostrados",
+    nothing_worth_selling: "Ahora mismo no vale la pena comprar nada con esta moneda.",
+    net: "neto",
+    vendor_it: "Véndelo al vendedor",
+    vendor_pays: "Un vendedor paga",
+    from_auction_house: "casa de subastas",
+    from_vendor: "vendedor",
+    crafted_makes: "fabricado, produce",
+    no_price: "sin precio",
+    for_currency: "por",
+};
+
+static RUSSIAN: UiStrings = UiStrings {
+    realm_list: "Список миров",
+    select_realm: "Выберите свой мир из списка, чтобы увидеть текущие цены.",
+    pick_region: "Выбрать другой регион",
+    current_values_for: "Текущие цены для",
+    still_waiting: "Для этого мира пока нет данных.",
+    last_updated: "Обновлено",
+    minute_ago: "минуту назад",
+    minutes_ago: "минут назад",
+    best_investment: "Лучшее вложение сейчас",
+    crafting_mats: "Материалы для изготовления",
+    region_source: "регион",
+    realm_source: "мир",
+    region_source_title: "Цена с аукциона товаров всего региона",
+    realm_source_title: "Цена с аукциона этого мира",
+    language: "Язык",
+    value_history: "История цены",
+    days: "дней",
+    all_time: "Всё",
+    not_enough_history: "Пока недостаточно данных для графика.",
+    back_to_realm: "Назад к миру",
+    latest: "Последняя",
+    lowest: "Минимум",
+    highest: "Максимум",
+    based_on: "на основе",
+    listings: "лотов",
+    units: "шт.",
+    ignored_as_outliers: "отброшены как выбросы",
+    optimizer: "Оптимизатор трат",
+    optimizer_explanation: "Продажа большого количества одного предмета переполняет его рынок, поэтому каждая следующая единица валюты, потраченная на него, стоит меньше предыдущей. Здесь валюта распределяется между предметами так, чтобы получить максимум, если сбивать цены конкурирующих лотов.",
+    currency_to_spend: "Сколько валюты потратить",
+    optimize: "Рассчитать",
+    spend: "Потратить",
+    expected_value: "Ожидаемая выручка",
+    last_one_worth: "Последняя стоит",
+    worth_of_the: "Стоимость единицы валюты №",
+    at_listed_values: "По указанным ценам",
+    nothing_worth_selling: "Сейчас на эту валюту ничего не стоит покупать.",
+    net: "чистыми",
+    vendor_it: "Продайте торговцу",
+    vendor_pays: "Торговец платит",
+    from_auction_house: "аукцион",
+    from_vendor: "торговец",
+    crafted_makes: "изготовлено, выход",
+    no_price: "нет цены",
+    for_currency: "за",
+};
+
+/// The UI text for a locale, falling back to English for languages we haven't translated.
+pub fn ui_strings(locale: &str) -> &'static UiStrings {
+    match locale.split('_').next().unwrap() {
+        "de" => &GERMAN,
+        "fr" => &FRENCH,
+        "es" => &SPANISH,
+        "ru" => &RUSSIAN,
+        _ => &ENGLISH,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::headers::{AcceptLanguage, Header};
+
+    use super::*;
+
+    fn accept_language(raw: &str) -> AcceptLanguage {
+        AcceptLanguage::parse_header(&[raw.as_bytes().to_vec()]).unwrap()
+    }
+
+    fn available() -> Vec<String> {
+        vec!["en_US".to_owned(), "de_DE".to_owned(), "fr_FR".to_owned()]
+    }
+
+    #[test]
+    fn test_query_parameter_wins() {
+        assert_eq!(negotiate_locale(Some("locale=de_DE"), Some(&accept_language("fr-FR")), &available(), "en_US"), "de_DE");
+        assert_eq!(negotiate_locale(Some("locale=xx_XX"), None, &available(), "en_US"), "en_US");
+    }
+
+    #[test]
+    fn test_accept_language() {
+        assert_eq!(negotiate_locale(None, Some(&accept_language("es-ES, de;q=0.8, en;q=0.5")), &available(), "en_US"), "de_DE");
+        assert_eq!(negotiate_locale(None, Some(&accept_language("en-GB")), &available(), "en_US"), "en_US");
+    }
+}
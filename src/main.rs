@@ -1,8 +1,7 @@
-#![feature(drain_filter, proc_macro, slice_patterns)]
+#![feature(proc_macro, slice_patterns)]
 
 extern crate hyper;
 extern crate iron;
-extern crate regex;
 extern crate router;
 extern crate serde;
 #[macro_use]
@@ -10,351 +9,1231 @@ extern crate serde_derive;
 extern crate serde_json;
 extern crate scoped_threadpool;
 extern crate tera;
+extern crate toml;
 
 use std::collections::{BTreeMap, HashMap};
+use std::cmp;
 use std::env;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
 use std::sync::{Arc, RwLock};
-use std::thread::sleep;
-use std::time::{Instant, Duration, SystemTime, UNIX_EPOCH};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use iron::headers::ContentType;
+use iron::headers::{AcceptLanguage, ContentType};
 use iron::prelude::*;
 use iron::status;
+use iron::Handler;
 use router::Router;
 use scoped_threadpool::Pool;
 use tera::{Context, Tera};
 
+pub mod api;
 pub mod battle_net_api_client;
+pub mod catalog;
+pub mod chart;
+pub mod config;
+pub mod crafting;
+pub mod export;
+pub mod fees;
+pub mod http_transport;
+pub mod locale;
+pub mod market_depth;
+pub mod outlier_filter;
+pub mod output;
+pub mod price_estimator;
+pub mod price_history;
+pub mod realm_reader;
+pub mod region;
+pub mod snapshot_store;
+#[cfg(test)]
+mod test_support;
 pub mod thread_throttler;
 
-use battle_net_api_client::{AuctionListing, BattleNetApiClient, Region};
+use battle_net_api_client::{ApiError, AuctionListing, BattleNetApiClient, ItemInfo, RetryPolicy};
+use catalog::{lint, read_catalog, unsectioned_items, watch, Catalog, CatalogError, SharedCatalog};
+use config::{config_env_var, Config, DEFAULT_CONFIG_FILE};
+use chart::{format_money, line_chart, sparkline};
+use crafting::{mat_cost, mat_plans, Plan, Source};
+use export::{export_realm, export_region, realm_file_name, realm_rows, region_file_name, region_rows, Format};
+use fees::FeeModel;
+use http_transport::FixtureTransport;
+use locale::{is_supported, negotiate_locale, ui_strings};
+use market_depth::{allocate, currency_marginal_value, marginal_values, Choice, MarketDepth};
+use outlier_filter::filter_outliers;
+use output::write_region;
+use price_estimator::{default_estimator, PriceEstimator};
+use price_history::{AuctionHouse, PriceHistory, PricePoint};
+use realm_reader::{read_region_forever, read_region_once, revalue_region, RegionState};
+use region::{Region, RegionInfo};
+use snapshot_store::SnapshotStore;
 
-/// Represents a single option available for sale from the blood vendor.
+/// A currency that vendors trade catalog items for.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Currency {
+    /// How items' `vendor_type` refers to it, e.g. "blood".
+    id: String,
+    name: String,
+    icon: String,
+    /// Where to spend it, for the realm page.
+    description: String,
+}
+
+/// The `vendor_type` of catalog items that aren't sold for any currency, only used as mats.
+const REAGENT: &'static str = "reagent";
+
+/// Represents a single option available for sale from a currency vendor.
 #[derive(Debug, Deserialize)]
-struct VendorItem {
+pub struct VendorItem {
     name: String,
-    quantity: f64,
+    /// Units of the item one exchange with the vendor gets.
+    quantity: u64,
+    /// Currency one exchange costs, e.g. 10 sargerite for a single gem.
+    #[serde(default = "default_cost")]
+    cost: u64,
     id: u64,
-    vendor_type: String,  // TODO: This should really be an enum populated by a custom deserializer.
+    vendor_type: String,  // The id of the currency it's sold for, or REAGENT.
     subtext: Option<String>,
+    /// What it takes besides currency to get one from the vendor.
     mats: Option<Vec<CraftingComponent>>,
+    /// Ways of crafting it, for when it's a mat in something else.
+    #[serde(default)]
+    recipes: Vec<Recipe>,
+    /// Copper an NPC sells one for, if one does.
+    vendor_price: Option<u64>,
+    /// Commodities trade on the region-wide auction house rather than per realm.
+    #[serde(default = "default_commodity")]
+    commodity: bool,
+    /// How to price this item, overriding the global estimator. See `parse_estimator`.
+    estimator: Option<String>,
+    #[serde(skip_deserializing, default = "default_estimator")]
+    price_estimator: Arc<PriceEstimator>,
+    /// What a vendor pays for one, from the item's info. Deposits are based on it.
+    #[serde(skip_deserializing)]
+    sell_price: u64,
+}
+
+impl VendorItem {
+    /// What one unit of currency is worth spent on this item, given what one unit of it is
+    /// worth, in copper. Rounds down, a fraction of a copper at most.
+    fn per_currency(&self, unit_value: u64) -> u64 {
+        unit_value * self.quantity / cmp::max(self.cost, 1)
+    }
+}
+
+/// Most exchanges are for a single unit of currency.
+fn default_cost() -> u64 {
+    1
+}
+
+/// Stackable trade goods are commodities, which is most of what vendors want from us.
+fn default_commodity() -> bool {
+    true
 }
 
 /// Some items we can 'buy' are actually crafted or traded with NPCs. This represents
 /// an ingredient in the recipe.
 #[derive(Debug, Deserialize)]
-struct CraftingComponent {
+pub struct CraftingComponent {
     id: u64,
     quantity: u64,
 }
 
+/// One way of crafting an item from other catalog items.
+#[derive(Debug, Deserialize)]
+pub struct Recipe {
+    mats: Vec<CraftingComponent>,
+    /// How many one craft makes.
+    #[serde(rename = "yield", default = "default_yield")]
+    yields: u64,
+}
+
+fn default_yield() -> u64 {
+    1
+}
+
 /// Value of an item on a realm.
-#[derive(Debug)]
-struct ItemValue {
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ItemValue {
     id: u64,
     value: u64,
+    /// What the seller keeps of `value` after the auction house's cut and deposits.
+    #[serde(default)]
+    net_value: u64,
+    /// Whether a vendor pays more than the auction house nets, so the values are the vendor's.
+    #[serde(default)]
+    vendor_it: bool,
 }
 
 /// The calculated values for items on a particular realm.
 #[derive(Debug)]
-struct CurrentRealmValues {
+pub struct CurrentRealmValues {
     last_update: u64,  // The last time we got this info, as reported by the Blizzard API.
     value_map: Arc<HashMap<u64, u64>>,
-    sargerite_item_values: Arc<Vec<ItemValue>>,
-    blood_item_values: Arc<Vec<ItemValue>>,  // Should be sorted by value.
+    /// What was listed of each item on this auction house in the last fetch.
+    markets: Arc<BTreeMap<u64, PricePoint>>,
+    /// The listings sellers of each item compete with, from the last fetch.
+    depths: Arc<BTreeMap<u64, MarketDepth>>,
+    /// Values of the items sold for each currency, keyed by currency id. Sorted by decreasing net value.
+    item_values: Arc<BTreeMap<String, Vec<ItemValue>>>,
+    /// The last fetch's listings of every item, grouped by `group_listings`. Kept so a new
+    /// catalog can be valued without downloading them again. Not saved across restarts.
+    listings: Arc<BTreeMap<u64, Vec<(u64, u64)>>>,
 }
 
-/// All the data in a single row in our price list for a realm.
-#[derive(Debug, Serialize)]
-struct PriceRow {
+impl CurrentRealmValues {
+    /// Values for a realm we haven't heard about yet.
+    fn empty() -> CurrentRealmValues {
+        CurrentRealmValues {
+            last_update: 0,
+            value_map: Arc::new(HashMap::new()),
+            markets: Arc::new(BTreeMap::new()),
+            depths: Arc::new(BTreeMap::new()),
+            item_values: Arc::new(BTreeMap::new()),
+            listings: Arc::new(BTreeMap::new()),
+        }
+    }
+}
+
+/// How far back the sparklines on the realm page go, in milliseconds.
+const SPARKLINE_PERIOD: u64 = 7 * 24 * 60 * 60 * 1000;
+
+/// Prices based on fewer listings than this are flagged as unreliable.
+const MIN_RELIABLE_LISTINGS: u64 = 5;
+
+/// The time ranges, in days, offered on an item's history page.
+const HISTORY_RANGES: [&'static str; 4] = ["7", "30", "90", "all"];
+
+/// How much currency the optimizer spreads unless told otherwise.
+const DEFAULT_OPTIMIZER_CURRENCY: u64 = 100;
+
+/// The most currency the optimizer will spread, so a silly request can't tie up the server.
+const MAX_OPTIMIZER_CURRENCY: u64 = 10_000;
+
+/// Which units of currency the optimizer shows the marginal value of for every item.
+const MARGINAL_VALUE_STEPS: [u64; 5] = [1, 10, 50, 100, 200];
+
+/// All the data in a single row in our price list for a realm. The JSON API serves these as they are.
+#[derive(Clone, Debug, Serialize)]
+pub struct PriceRow {
+    id: u64,
     name: String,
-    quantity: f64,
+    quantity: u64,  // Units one exchange gets.
+    cost: u64,  // Currency one exchange costs.
     icon: String,
     subtext: String,
     vendor_type: String,
+    source: String,  // "region" for commodity auction prices, "realm" for the realm's own auction house.
+    value: u64,  // In copper.
+    net_value: u64,  // What the seller keeps after the auction house's cut and deposits.
+    net_money: String,  // net_value as gold, silver and copper.
+    vendor_it: bool,  // Whether a vendor pays more than the auction house.
+    sell_price: u64,  // What a vendor pays for one.
+    quality: u64,  // 0 for poor through 5 for legendary.
+    item_level: u64,
+    estimator: String,  // How the prices behind the value were estimated.
+    listings: u64,
+    discarded_listings: u64,  // Left out of the price as outliers.
+    discarded_quantity: u64,
+    few_listings: bool,  // Whether the price rests on too few listings to trust.
     value_ratio: u64,
     gold: u64,
     silver: u64,
     copper: u64,
     mats: Vec<Material>,
+    sparkline: String,  // SVG of the value over the last week.
+}
+
+/// The rows for one currency on a realm's price page.
+#[derive(Debug, Serialize)]
+pub struct CurrencySection {
+    currency: Currency,
+    price_rows: Vec<PriceRow>,
 }
 
+/// A link to a realm's price page on the realm list.
 #[derive(Debug, Serialize)]
-struct Material {
+struct RealmLink {
+    slug: String,
     name: String,
+}
+
+/// A step in the cheapest crafting path for a vendor item's mats.
+#[derive(Clone, Debug, Serialize)]
+pub struct Material {
+    id: u64,
+    name: String,
+    quantity: u64,  // Per vendor item, or per craft of the material above.
+    value: u64,  // In copper, for the whole quantity.
     gold: u64,
     silver: u64,
     copper: u64,
+    depth: usize,  // 0 for the vendor item's own mats, 1 for theirs and so on.
+    source: String,  // "auction", "vendor", "craft" or "unknown" if we couldn't price it.
+    how: String,  // Where it comes from, in the page's locale.
 }
-/// Number of threads to use when fetching auction house results.
-const NUM_AUCTION_DATA_THREADS: u32 = 5;
 
-/// Number of seconds to wait between fetching new auction results.
-const RESULT_FETCH_PERIOD: u64 = 60 * 30;
+/// How much of the currency to spend on one vendor item, on the optimizer page.
+#[derive(Debug, Serialize)]
+struct AllocationRow {
+    id: u64,
+    name: String,
+    icon: String,
+    currency: u64,
+    units: u64,  // Of the item the currency buys.
+    value: String,
+    last_marginal_value: String,
+}
 
-/// Given a vec of auction listings for a realm and a map of the items we care about,
-/// returns a vec of (item_id, value) sorted by decreasing value, where value is
-/// based on the 5th percentile buyout price.
-fn calculate_auction_values(listings: &Vec<AuctionListing>, items: &HashMap<u64, VendorItem>) ->
-    (HashMap<u64, u64>, Vec<ItemValue>, Vec<ItemValue>) {
-    // Calculate 5th percentiles for the items we care about.
+/// What spending more and more currency on one vendor item is worth, on the optimizer page.
+#[derive(Debug, Serialize)]
+struct MarginalValueRow {
+    id: u64,
+    name: String,
+    icon: String,
+    values: Vec<String>,  // One per step in MARGINAL_VALUE_STEPS.
+}
+
+/// Groups one fetch's auction listings into (quantity, unit price) listings for each item,
+/// sorted by unit price. Every item is kept, not just the catalog's, so items added to the
+/// catalog later can be valued without downloading the listings again.
+fn group_listings(listings: &Vec<AuctionListing>) -> BTreeMap<u64, Vec<(u64, u64)>> {
     let mut price_points: BTreeMap<u64, Vec<(u64, u64)>> = BTreeMap::new();
     for listing in listings {
-        if items.contains_key(&listing.item) && listing.buyout > 0 {
+        if listing.buyout > 0 {
             price_points.entry(listing.item).or_insert(Vec::new()).push((listing.quantity, listing.buyout / listing.quantity));
         }
     }
-    for quantities_and_buyouts in price_points.values_mut() {
-        quantities_and_buyouts.sort_by_key(|a| a.1);  // Sort by buyout price.
-    }
-    let total_item_quantities: BTreeMap<u64, u64> =
-        price_points.iter().map(|(k, v)| {
-            (*k, v.iter().fold(0, |sum, quantity_and_buyout| sum + quantity_and_buyout.0))
-        }).collect();
-    let fifth_percentile_price_points: HashMap<u64, u64> =
-        price_points.iter().map(|(item_id, ref item_listings)| {
-            let fifth_percentile_quantity = total_item_quantities.get(item_id).unwrap() / 20;
-            let mut running_sum: u64 = 0;
-            let fifth_percentile_listing = item_listings.iter().find(|&&(quantity, _)| {
-                running_sum += quantity;
-                running_sum >= fifth_percentile_quantity
-            }).unwrap();
-            (*item_id, fifth_percentile_listing.1)
-        }).collect();
-    let mut item_values: Vec<ItemValue> = items.values().map(|item| {
-        let mut value = *fifth_percentile_price_points.get(&item.id).unwrap_or(&0u64);
-        value = match &item.mats {  // Subtract the costs of mats if any.
-            &Some(ref mats_list) => {
-                // Sum up the prices.
-                let mat_cost = mats_list.iter().map(
-                     |&ref x| x.quantity * fifth_percentile_price_points.get(&x.id).unwrap_or(&0u64)
-                ).sum();
-                if value >= mat_cost {
-                    value - mat_cost
-                } else {
-                    0
-                }
-            },
-            &None => value
+    for item_listings in price_points.values_mut() {
+        item_listings.sort_by_key(|a| a.1);  // Sort by buyout price.
+    }
+    price_points
+}
+
+/// Summarizes the grouped listings of the items we care about: the unit price according to
+/// the item's estimator once outliers are filtered out, the total quantity listed, the number
+/// of listings and how many of those were discarded as outliers.
+fn summarize_listings(timestamp: u64, listings: &BTreeMap<u64, Vec<(u64, u64)>>, items: &HashMap<u64, VendorItem>)
+    -> (BTreeMap<u64, PricePoint>, BTreeMap<u64, MarketDepth>) {
+    let mut markets = BTreeMap::new();
+    let mut depths = BTreeMap::new();
+    for (&item_id, item_listings) in listings {
+        let item = match items.get(&item_id) {
+            Some(item) => item,
+            None => continue,
+        };
+        let item_listings = item_listings.clone();
+        let total_quantity = item_listings.iter().fold(0, |sum, quantity_and_buyout| sum + quantity_and_buyout.0);
+        let filtered = filter_outliers(&item_listings);
+        let listing_count = item_listings.len() as u64;
+        // If nothing survives filtering, the raw listings are still better than no price at all.
+        // Holding listings to their share only matters to the price; what the market will take
+        // depends on every unit listed.
+        let (estimate_from, depth_from) = if filtered.listings.is_empty() {
+            (item_listings.clone(), item_listings)
+        } else {
+            (filtered.listings, filtered.uncapped_listings)
         };
-        ItemValue {
+        let price = item.price_estimator.estimate(&estimate_from);
+        markets.insert(item_id, PricePoint {
+            timestamp: timestamp,
+            price: price,
+            quantity: total_quantity,
+            listings: listing_count,
+            discarded_listings: filtered.discarded_listings,
+            discarded_quantity: filtered.discarded_quantity,
+            value: None,
+        });
+        depths.insert(item_id, MarketDepth::new(depth_from, price));
+    }
+    (markets, depths)
+}
+
+/// Given a summary of an auction house and a map of the items we care about, returns the
+/// estimated unit price of each listed item layered over `base_prices`, and the values of the
+/// items whose `commodity` flag matches `commodities` by the currency they're sold for, each
+/// sorted by decreasing net value.
+/// `base_prices` lets per-realm crafting costs use region-wide commodity prices for mats.
+fn calculate_auction_values(markets: &BTreeMap<u64, PricePoint>, items: &HashMap<u64, VendorItem>,
+                            commodities: bool, base_prices: &HashMap<u64, u64>, fees: &FeeModel) ->
+    (HashMap<u64, u64>, BTreeMap<String, Vec<ItemValue>>) {
+    let mut unit_prices: HashMap<u64, u64> = base_prices.clone();
+    unit_prices.extend(markets.iter().map(|(&item_id, price_point)| (item_id, price_point.price)));
+    let mut item_values: BTreeMap<String, Vec<ItemValue>> = BTreeMap::new();
+    for item in items.values().filter(|item| item.commodity == commodities && item.vendor_type != REAGENT) {
+        let price = *unit_prices.get(&item.id).unwrap_or(&0u64);
+        // Subtract the costs of mats if any.
+        let mat_cost = mat_cost(item, items, &unit_prices);
+        // A vendor always buys, so nothing is worth less than what it pays, and selling to one is free.
+        let net_price = fees.net_unit_price(price, item.sell_price);
+        let vendor_it = item.sell_price > net_price;
+        let (price, net_price) = if vendor_it { (item.sell_price, item.sell_price) } else { (price, net_price) };
+        let value = price.saturating_sub(mat_cost);
+        let net_value = net_price.saturating_sub(mat_cost);
+        item_values.entry(item.vendor_type.clone()).or_insert(Vec::new()).push(ItemValue {
             id: item.id,
-            value: match item.quantity {
-                // A bit of a hack to avoid propagating floating point madness through the codebase:
-                // Some items are 10 primal sargerite for 1 gem. All the others are whole numbers. So
-                // we special case the former case as integer division.
-                0.1 => value / 10,
-                _ => value * item.quantity as u64,
+            value: item.per_currency(value),
+            net_value: item.per_currency(net_value),
+            vendor_it: vendor_it,
+        });
+    }
+    for values in item_values.values_mut() {
+        values.sort_by_key(|item_value| !item_value.net_value);
+    }
+
+    (unit_prices, item_values)
+}
+
+/// Returns the value of the first `name` parameter in a query string.
+fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query.and_then(|query| query.split('&').find(|param| param.starts_with(name) && param[name.len()..].starts_with('=')))
+        .map(|param| &param[name.len() + 1..])
+}
+
+/// Milliseconds since the epoch, the unit Blizzard timestamps are in.
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() * 1000
+}
+
+/// Everything the web pages need that isn't specific to a region.
+pub struct Site {
+    catalog: Arc<SharedCatalog>,
+    /// Locales pages can be rendered in.
+    locales: Vec<String>,
+    price_history: Arc<PriceHistory>,
+    /// What selling on the auction house costs.
+    fees: Arc<FeeModel>,
+    tera: Tera,
+}
+
+impl Site {
+    /// Picks the locale to render a request in.
+    fn locale_for(&self, req: &Request, state: &RegionState) -> String {
+        negotiate_locale(req.url.query(), req.headers.get::<AcceptLanguage>(), &self.locales,
+                         state.region.info().default_locale)
+    }
+
+    /// Adds what every page needs to know about the locale it's rendered in.
+    fn add_locale_to_context(&self, context: &mut Context, locale: &str) {
+        context.add("locale", &locale);
+        context.add("locales", &self.locales);
+        context.add("t", ui_strings(locale));
+    }
+
+    /// The catalog in use. A page should stick to the one it started with.
+    fn catalog(&self) -> Arc<Catalog> {
+        self.catalog.get()
+    }
+
+    /// The recorded value of an item on an auction house since `since`, as (timestamp, value) points.
+    fn value_history(&self, region: Region, auction_house: AuctionHouse, id: u64, since: u64) -> Vec<(u64, u64)> {
+        match self.price_history.series(region, auction_house, id, since) {
+            Ok(series) => series.into_iter().filter_map(|point| point.value.map(|value| (point.timestamp, value))).collect(),
+            Err(e) => {
+                println!("Unable to read {} price history for item {}: {}", region, id, e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Builds a section of rows for each currency on a connected realm, merging in the region-wide
+    /// commodity values. Rows are sorted by decreasing net value and have no sparklines yet.
+    // TODO: Things would actually be even cleaner probably if
+    // we did all this work in the background thread; it doesn't
+    // change per request. Then the router methods would basically
+    // just take a read lock on the values, clone some things,
+    // and build the contexts.
+    fn price_rows(&self, catalog: &Catalog, realm_prices: &CurrentRealmValues, commodity_prices: &CurrentRealmValues,
+                  locale: &str) -> Vec<CurrencySection> {
+        let items = &catalog.items;
+        let unit_prices = self.unit_prices(realm_prices, commodity_prices);
+        // Closure that merges the region-wide and per-realm vectors of
+        // ItemValues and processes them into the rows that we need.
+        let make_price_rows = |region_values: &Vec<ItemValue>, realm_values: &Vec<ItemValue>| -> Vec<PriceRow> {
+            // Values worked out before a catalog reload can include items that have since left it.
+            let mut x: Vec<(&ItemValue, &str)> = region_values.iter().map(|v| (v, "region"))
+                .chain(realm_values.iter().map(|v| (v, "realm")))
+                .filter(|&(item_value, _)| items.contains_key(&item_value.id))
+                .collect();
+            x.sort_by_key(|&(item_value, _)| !item_value.net_value);
+            let highest_value = match x.first() {
+                Some(&(item_value, _)) => item_value.net_value,
+                None => 0
+            };
+            x.iter().map(|&(&ItemValue{id, value, net_value, vendor_it}, source)| {
+                let item_info = items.get(&id).unwrap();
+                // Items a reload just added may not have their info yet.
+                let details = catalog.item_infos.get(&id);
+                let gold = value / (10_000);
+                let silver = (value - gold * 10_000) / 100;
+                let copper = value - gold * 10_000 - silver * 100;
+                let value_ratio = match highest_value {
+                    0u64 => 0u64,
+                    _ => net_value*100/highest_value,  // Percentile!
+                };
+                let markets = if source == "region" { &commodity_prices.markets } else { &realm_prices.markets };
+                let (listings, discarded_listings, discarded_quantity) = match markets.get(&id) {
+                    Some(market) => (market.listings, market.discarded_listings, market.discarded_quantity),
+                    None => (0, 0, 0),
+                };
+                PriceRow {
+                    id: id,
+                    name: catalog.item_name(id, locale),
+                    quantity: item_info.quantity,
+                    cost: item_info.cost,
+                    icon: catalog.item_icon(id),
+                    subtext: item_info.subtext.clone().unwrap_or(String::new()),
+                    vendor_type: item_info.vendor_type.clone(),
+                    source: source.to_owned(),
+                    value: value,
+                    net_value: net_value,
+                    net_money: format_money(net_value),
+                    vendor_it: vendor_it,
+                    sell_price: item_info.sell_price,
+                    quality: details.and_then(|details| details.quality).unwrap_or(1),
+                    item_level: details.and_then(|details| details.item_level).unwrap_or(0),
+                    estimator: item_info.price_estimator.describe(),
+                    listings: listings,
+                    discarded_listings: discarded_listings,
+                    discarded_quantity: discarded_quantity,
+                    few_listings: listings - discarded_listings < MIN_RELIABLE_LISTINGS,
+                    value_ratio: value_ratio,
+                    gold: gold,
+                    silver: silver,
+                    copper: copper,
+                    mats: {
+                        let mut mats = Vec::new();
+                        for (mat_id, quantity, plan) in mat_plans(item_info, items, &unit_prices) {
+                            self.add_materials(catalog, &mut mats, mat_id, quantity, plan.as_ref(), 0, locale);
+                        }
+                        mats
+                    },
+                    sparkline: String::new(),
+                }
+            }).collect()
+        };
+        let no_values = Vec::new();
+        catalog.currencies.iter().map(|currency| CurrencySection {
+            currency: currency.clone(),
+            price_rows: make_price_rows(commodity_prices.item_values.get(&currency.id).unwrap_or(&no_values),
+                                        realm_prices.item_values.get(&currency.id).unwrap_or(&no_values)),
+        }).collect()
+    }
+
+    /// Estimated unit prices for a connected realm, falling back to region-wide commodity prices.
+    fn unit_prices(&self, realm_prices: &CurrentRealmValues, commodity_prices: &CurrentRealmValues) -> HashMap<u64, u64> {
+        let mut unit_prices = (*commodity_prices.value_map).clone();
+        unit_prices.extend(realm_prices.value_map.iter().map(|(&id, &price)| (id, price)));
+        unit_prices
+    }
+
+    /// Adds `quantity` of a mat and, if it's crafted, everything that goes into it to `mats`.
+    fn add_materials(&self, catalog: &Catalog, mats: &mut Vec<Material>, id: u64, quantity: u64, plan: Option<&Plan>,
+                     depth: usize, locale: &str) {
+        let t = ui_strings(locale);
+        let value = quantity * plan.map(|plan| plan.unit_cost).unwrap_or(0);
+        let (source, how) = match plan.map(|plan| &plan.source) {
+            Some(&Source::AuctionHouse) => ("auction", t.from_auction_house.to_owned()),
+            Some(&Source::Vendor) => ("vendor", t.from_vendor.to_owned()),
+            Some(&Source::Craft { yields, .. }) => ("craft", format!("{} {}", t.crafted_makes, yields)),
+            None => ("unknown", t.no_price.to_owned()),
+        };
+        mats.push(Material {
+            id: id,
+            name: catalog.item_name(id, locale),
+            quantity: quantity,
+            value: value,
+            gold: value / 10_000,
+            silver: value / 100 % 100,
+            copper: value % 100,
+            depth: depth,
+            source: source.to_owned(),
+            how: how,
+        });
+        if let Some(&Plan { source: Source::Craft { mats: ref sub_mats, .. }, .. }) = plan {
+            for &(sub_quantity, ref sub_plan) in sub_mats {
+                self.add_materials(catalog, mats, sub_plan.id, sub_quantity, Some(sub_plan), depth + 1, locale);
             }
         }
+    }
+
+    /// When the stalest source a realm's values depend on was last updated, or 0 if we're still waiting on one.
+    fn last_update(&self, catalog: &Catalog, realm_prices: &CurrentRealmValues, commodity_prices: &CurrentRealmValues)
+        -> u64 {
+        if catalog.has_realm_items() {
+            cmp::min(commodity_prices.last_update, realm_prices.last_update)
+        } else {
+            commodity_prices.last_update
+        }
+    }
+}
+
+/// Renders the price page for `realm`, whose connected realm's values are behind `realm_prices_lock`.
+fn render_realm_prices(site: &Site, state: &RegionState, realm: &str, realm_prices_lock: &RwLock<CurrentRealmValues>,
+                       locale: &str) -> IronResult<Response> {
+    let catalog = site.catalog();
+    let mut context = Context::new();
+    let realm_prices = realm_prices_lock.read().unwrap();
+    let commodity_prices = state.commodity_values.read().unwrap();
+    let connected_realm_id = *state.realm_aliases.get(realm).unwrap();
+    let sparkline_start = now_millis() - SPARKLINE_PERIOD;
+
+    let mut sections = site.price_rows(&catalog, &realm_prices, &commodity_prices, locale);
+    for price_row in sections.iter_mut().flat_map(|section| section.price_rows.iter_mut()) {
+        price_row.sparkline = sparkline(&site.value_history(state.region, catalog.auction_house_for(price_row.id, connected_realm_id),
+                                                            price_row.id, sparkline_start), 120, 30);
+    }
+    context.add("realm_name", &state.realms.iter().find(|&realm_info| &realm_info.slug == realm).unwrap().localized_name(locale));
+    context.add("sections", &sections);
+    // Report the age of the stalest source the page depends on.
+    // TODO: Change this to something more human readable.
+    let last_update = site.last_update(&catalog, &realm_prices, &commodity_prices);
+    if last_update == 0 {
+        context.add("update_age", &-1);
+    } else {
+        context.add("update_age",
+            &((SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() - last_update / 1000) / 60));
+    }
+    context.add("region", state.region.info());
+    context.add("fees", &site.fees.describe());
+    context.add("realm_slug", &realm);
+    site.add_locale_to_context(&mut context, locale);
+    Ok(Response::with((ContentType::html().0, status::Ok, site.tera.render("prices.html", context).unwrap())))
+}
+
+/// Renders the history of an item's value on `realm` over the last `days` days, or all of it.
+fn render_item_history(site: &Site, catalog: &Catalog, state: &RegionState, realm: &str, id: u64, days: &str,
+                       locale: &str) -> IronResult<Response> {
+    let since = match days.parse::<u64>() {
+        Ok(days) => now_millis().saturating_sub(days * 24 * 60 * 60 * 1000),
+        Err(_) => 0,
+    };
+    let connected_realm_id = *state.realm_aliases.get(realm).unwrap();
+    let history = site.value_history(state.region, catalog.auction_house_for(id, connected_realm_id), id, since);
+    let mut context = Context::new();
+    context.add("realm_name", &state.realms.iter().find(|&realm_info| &realm_info.slug == realm).unwrap().localized_name(locale));
+    context.add("realm_slug", &realm);
+    context.add("item_id", &id);
+    context.add("item_name", &catalog.item_name(id, locale));
+    context.add("icon", &catalog.item_icon(id));
+    context.add("chart", &line_chart(&history, 800, 300));
+    context.add("days", &days);
+    context.add("ranges", &HISTORY_RANGES);
+    if let Some(&(_, latest)) = history.last() {
+        context.add("latest", &format_money(latest));
+        context.add("lowest", &format_money(history.iter().map(|p| p.1).min().unwrap()));
+        context.add("highest", &format_money(history.iter().map(|p| p.1).max().unwrap()));
+    }
+    context.add("region", state.region.info());
+    site.add_locale_to_context(&mut context, locale);
+    Ok(Response::with((ContentType::html().0, status::Ok, site.tera.render("item.html", context).unwrap())))
+}
+
+/// Renders the optimizer page, splitting `amount` of `currency` across the items sold for it on `realm`.
+fn render_optimizer(site: &Site, catalog: &Catalog, state: &RegionState, realm: &str, currency: &Currency,
+                    amount: u64, locale: &str) -> IronResult<Response> {
+    let realm_prices = state.realm_values(realm).unwrap().read().unwrap();
+    let commodity_prices = state.commodity_values.read().unwrap();
+    let mut items: Vec<&VendorItem> = catalog.items.values().filter(|item| item.vendor_type == currency.id).collect();
+    items.sort_by_key(|item| item.id);
+    let unit_prices = site.unit_prices(&realm_prices, &commodity_prices);
+    let choices: Vec<Choice> = items.iter().filter_map(|item| {
+        let values = if item.commodity { &commodity_prices } else { &realm_prices };
+        values.depths.get(&item.id).map(|depth| Choice {
+            id: item.id,
+            cost: item.cost,
+            quantity: item.quantity,
+            mat_cost: mat_cost(item, &catalog.items, &unit_prices),
+            sell_price: item.sell_price,
+            depth: depth,
+            fees: &site.fees,
+        })
     }).collect();
-    item_values.sort_by_key(|item_value| !item_value.value);
 
-    // NOTE: drain_filter() is a nightly only experimental API call that might break.
-    let blood_item_values = item_values.drain_filter(|x| items.get(&x.id).unwrap().vendor_type.eq("blood"))
-        .collect::<Vec<_>>();
-    let sargerite_item_values = item_values.drain_filter(|x| items.get(&x.id).unwrap().vendor_type.eq("sargerite"))
-        .collect::<Vec<_>>();
+    let allocations = allocate(&choices, amount);
+    let spent: u64 = allocations.iter().map(|allocation| allocation.currency).sum();
+    let total: u64 = allocations.iter().map(|allocation| allocation.value).sum();
+    let allocation_rows: Vec<AllocationRow> = allocations.iter().map(|allocation| AllocationRow {
+        id: allocation.id,
+        name: catalog.item_name(allocation.id, locale),
+        icon: catalog.item_icon(allocation.id),
+        currency: allocation.currency,
+        units: allocation.exchanges * catalog.items.get(&allocation.id).unwrap().quantity,
+        value: format_money(allocation.value),
+        last_marginal_value: format_money(allocation.last_marginal_value),
+    }).collect();
+    let last_step = MARGINAL_VALUE_STEPS[MARGINAL_VALUE_STEPS.len() - 1];
+    let marginal_value_rows: Vec<MarginalValueRow> = choices.iter().map(|choice| {
+        let values = marginal_values(choice, (last_step + choice.cost - 1) / choice.cost);
+        MarginalValueRow {
+            id: choice.id,
+            name: catalog.item_name(choice.id, locale),
+            icon: catalog.item_icon(choice.id),
+            values: MARGINAL_VALUE_STEPS.iter()
+                .map(|&step| format_money(currency_marginal_value(choice, &values, step))).collect(),
+        }
+    }).collect();
+    // What the headline net values on the realm page would have promised for the same currency.
+    let best_value = commodity_prices.item_values.get(&currency.id).into_iter()
+        .chain(realm_prices.item_values.get(&currency.id).into_iter())
+        .flat_map(|values| values.iter())
+        .map(|item_value| item_value.net_value)
+        .max()
+        .unwrap_or(0);
 
-    (fifth_percentile_price_points, blood_item_values, sargerite_item_values)
+    let mut context = Context::new();
+    context.add("realm_name", &state.realms.iter().find(|&realm_info| &realm_info.slug == realm).unwrap().localized_name(locale));
+    context.add("realm_slug", &realm);
+    context.add("currency", currency);
+    context.add("currencies", &catalog.currencies);
+    context.add("amount", &amount);
+    context.add("allocation_rows", &allocation_rows);
+    context.add("total", &format_money(total));
+    context.add("unspent", &(amount - spent));
+    context.add("at_listed_values", &format_money(best_value * amount));
+    context.add("steps", &MARGINAL_VALUE_STEPS);
+    context.add("marginal_value_rows", &marginal_value_rows);
+    context.add("region", state.region.info());
+    site.add_locale_to_context(&mut context, locale);
+    Ok(Response::with((ContentType::html().0, status::Ok, site.tera.render("optimize.html", context).unwrap())))
 }
 
-/// Given a battle net Region, return the first path of the app's URL.
-fn app_url_for_region(region: &Region) -> &'static str {
-    match region {
-        &Region::US => "blood-money",
-        &Region::EU => "blood-money-eu",
+/// Mounts a region's realm list, realm price pages, item history pages, optimizers and exports under its URL prefix.
+fn add_region_routes(router: &mut Router, state: Arc<RegionState>, site: Arc<Site>) {
+    let url_prefix = state.region.info().url_prefix;
+    {
+        let state = state.clone();
+        let site = site.clone();
+        let route_id = format!("index-{}", state.region);
+        router.get(format!("/{}", url_prefix), move |req: &mut Request| {
+            let locale = site.locale_for(req, &state);
+            let mut realm_links: Vec<RealmLink> = state.realms.iter().map(|realm| RealmLink {
+                slug: realm.slug.clone(),
+                name: realm.localized_name(&locale).to_owned(),
+            }).collect();
+            realm_links.sort_by(|a, b| a.name.cmp(&b.name));
+            let mut context = Context::new();
+            context.add("realms", &realm_links);
+            context.add("region", state.region.info());
+            site.add_locale_to_context(&mut context, &locale);
+            Ok(Response::with((ContentType::html().0, status::Ok, site.tera.render("index.html", context).unwrap())))
+        }, route_id);
+    }
+    {
+        let state = state.clone();
+        let site = site.clone();
+        let route_id = format!("item-history-{}", state.region);
+        router.get(format!("/{}/:realm/item/:id", url_prefix), move |req: &mut Request| {
+            let locale = site.locale_for(req, &state);
+            let days = match query_param(req.url.query(), "days") {
+                Some(days) if HISTORY_RANGES.contains(&days) => days.to_owned(),
+                _ => HISTORY_RANGES[0].to_owned(),
+            };
+            let params = req.extensions.get::<Router>().unwrap();
+            let realm = params.find("realm").unwrap();
+            let catalog = site.catalog();
+            let id = match params.find("id").and_then(|id| id.parse::<u64>().ok()) {
+                Some(id) if catalog.items.get(&id).map(|item| item.vendor_type != REAGENT).unwrap_or(false) => id,
+                _ => return Ok(Response::with(status::NotFound)),
+            };
+            if state.realm_aliases.contains_key(realm) {
+                render_item_history(&site, &catalog, &state, realm, id, &days, &locale)
+            } else {
+                Ok(Response::with(status::NotFound))
+            }
+        }, route_id);
+    }
+    {
+        let state = state.clone();
+        let site = site.clone();
+        let route_id = format!("optimize-{}", state.region);
+        router.get(format!("/{}/:realm/optimize", url_prefix), move |req: &mut Request| {
+            let locale = site.locale_for(req, &state);
+            let catalog = site.catalog();
+            let currency = catalog.currency(query_param(req.url.query(), "currency"));
+            let amount = query_param(req.url.query(), "amount").and_then(|amount| amount.parse::<u64>().ok())
+                .map(|amount| cmp::min(amount, MAX_OPTIMIZER_CURRENCY))
+                .unwrap_or(DEFAULT_OPTIMIZER_CURRENCY);
+            let realm = req.extensions.get::<Router>().unwrap().find("realm").unwrap();
+            if state.realm_aliases.contains_key(realm) {
+                render_optimizer(&site, &catalog, &state, realm, currency, amount, &locale)
+            } else {
+                Ok(Response::with(status::NotFound))
+            }
+        }, route_id);
+    }
+    for &format in &[Format::Csv, Format::Tsv] {
+        let state = state.clone();
+        let site = site.clone();
+        let file_name = match format {
+            Format::Csv => "export.csv",
+            Format::Tsv => "export.tsv",
+        };
+        let route_id = format!("{}-{}", file_name, state.region);
+        router.get(format!("/{}/{}", url_prefix, file_name), move |req: &mut Request| {
+            let locale = site.locale_for(req, &state);
+            export_region(&site, &state, format, &locale)
+        }, route_id);
     }
+    let route_id = format!("realm-prices-{}", state.region);
+    router.get(format!("/{}/:realm", url_prefix), move |req : &mut Request| {
+        let locale = site.locale_for(req, &state);
+        let realm = req.extensions.get::<Router>().unwrap().find("realm").unwrap();
+        // "<realm>.csv" and "<realm>.tsv" export the realm's values instead.
+        if let Some((realm, format)) = Format::from_file_name(realm) {
+            return if state.realm_values(realm).is_some() {
+                export_realm(&site, &state, realm, format, &locale)
+            } else {
+                Ok(Response::with(status::NotFound))
+            };
+        }
+        if let Some(realm_prices_lock) = state.realm_values(realm) {
+            render_realm_prices(&site, &state, realm, realm_prices_lock, &locale)
+        } else {
+            return Ok(Response::with(status::NotFound));
+        }
+    }, route_id);
 }
 
-fn main() {
-    let token = match env::args().nth(1) {
-        Some(token) => token,
-        None => {
-            println!("Usage: bloodmoney <api token> (us|eu)");
-            return;
+/// Info for every item in `items`, reusing anything in `known` that has every detail and
+/// locale we need and downloading the rest.
+fn item_infos_for(client: &BattleNetApiClient, items: &HashMap<u64, VendorItem>, locales: &[String],
+                  mut known: HashMap<u64, ItemInfo>) -> Result<HashMap<u64, ItemInfo>, ApiError> {
+    items.keys()
+        .map(|&id| match known.remove(&id) {
+            Some(info) if info.has_details() && locales.iter().all(|locale| info.names.contains_key(locale)) =>
+                Ok((id, info)),
+            _ => client.get_item_info(id, locales).map(|info| (id, info)),
+        })
+        .collect()
+}
+
+const USAGE: &'static str = "\
+Usage: bloodmoney <command> [--config=<file>] [--<setting>=<value>]...
+
+Commands:
+  serve [<region>...]          Serve the site, fetching auction data periodically
+  fetch-once [<region>...] [--buyout-listings]
+                               Fetch auction data once, write it to output_dir and exit
+  export <region> [<realm>] [--format=csv|tsv] [--locale=<locale>] [--output=<file>]
+                               Write saved values as CSV or TSV
+  catalog check [--fixtures=<dir>] [--cached]
+                               Check the catalog
+
+Settings are read from blood-money.toml, or the file named by --config or BLOOD_MONEY_CONFIG,
+then from BLOOD_MONEY_<SETTING> environment variables, then from flags like --data-dir=<dir>.";
+
+/// Reads the config for a command: the config file, then the environment, then any
+/// `--<setting>=<value>` flags in `args`, spelled with dashes, e.g. `--data-dir`. `options` are
+/// the command's own flags, which are returned along with the rest of its arguments.
+fn read_config(args: &[String], options: &[&str]) -> Result<(Config, Vec<String>), String> {
+    let mut config = Config::default();
+    let mut config_file = env::var(config_env_var()).ok();
+    let mut settings = Vec::new();
+    let mut rest = Vec::new();
+    for arg in args {
+        if arg.starts_with("--config=") {
+            config_file = Some(arg["--config=".len()..].to_owned());
+        } else if arg.starts_with("--") && arg.contains('=') {
+            let (name, value) = arg[2..].split_at(arg.find('=').unwrap() - 2);
+            if options.contains(&name) {
+                rest.push(arg.clone());
+            } else {
+                settings.push((name.to_owned(), value[1..].to_owned()));
+            }
+        } else {
+            rest.push(arg.clone());
         }
+    }
+    let applied = match config_file {
+        Some(ref path) => config.apply_file(Path::new(path)),
+        None => match config.apply_file(Path::new(DEFAULT_CONFIG_FILE)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Ok(())),
+            applied => applied,
+        },
     };
-    let locale = match env::args().nth(2) {
-        Some(ref s) if s == "us" => Region::US,
-        Some(ref s) if s == "eu" => Region::EU,
-        _ => {
-            println!("Usage: bloodmoney <api token> (us|eu)");
-            return;
-        }
+    match applied {
+        Ok(result) => try!(result),
+        Err(e) => return Err(format!("Unable to read config file {}: {}",
+                                     config_file.as_ref().map(|path| path.as_str()).unwrap_or(DEFAULT_CONFIG_FILE), e)),
+    }
+    try!(config.apply_env(env::vars()));
+    for (name, value) in settings {
+        try!(config.set(&name.replace('-', "_"), &value).map_err(|e| format!("--{}: {}", name, e)));
+    }
+    Ok((config, rest))
+}
+
+/// Regions named on the command line replace the configured ones.
+fn set_regions(config: &mut Config, args: &[String]) -> Result<(), String> {
+    if let Some(arg) = args.iter().find(|arg| arg.starts_with("--")) {
+        return Err(format!("Unknown option {}. {}", arg, USAGE));
+    }
+    if !args.is_empty() {
+        try!(config.set("regions", &args.join(",")));
+    }
+    Ok(())
+}
+
+/// What the commands working with auction data share: the catalog with its item info, and
+/// each region's realms with the values saved from before.
+struct Setup {
+    client: BattleNetApiClient<'static>,
+    snapshot_store: Arc<SnapshotStore>,
+    price_history: Arc<PriceHistory>,
+    shared_catalog: Arc<SharedCatalog>,
+    region_states: Vec<Arc<RegionState>>,
+    locales: Vec<String>,
+}
+
+/// Opens the data directory, reads the catalog and downloads what it needs, then gets each
+/// configured region ready to fetch.
+fn set_up(config: &Config) -> Result<Setup, String> {
+    if config.regions.is_empty() {
+        return Err(format!("No regions. Name them on the command line or set regions in the config. {}", USAGE));
+    }
+    if config.credentials.client_id.is_empty() || config.credentials.client_secret.is_empty() {
+        return Err("No API credentials. Set client_id and client_secret in the config, or BLOOD_MONEY_CLIENT_ID \
+                    and BLOOD_MONEY_CLIENT_SECRET in the environment.".to_owned());
+    }
+    let locales = config.all_locales();
+    let client = BattleNetApiClient::new(config.credentials.clone(), config.regions[0]);
+    let snapshot_store = match SnapshotStore::new(config.data_dir.as_str()) {
+        Ok(snapshot_store) => Arc::new(snapshot_store),
+        Err(e) => return Err(format!("Unable to open data directory {}: {}", config.data_dir, e)),
     };
-    let client = Arc::new(BattleNetApiClient::new(&token, locale));
-
-    // Process our item options and grab their icon names.
-    let items: Vec<VendorItem> = serde_json::from_str(include_str!("../catalog/items.json"))
-        .expect("Error reading items.");
-    let item_id_map: Arc<HashMap<u64, VendorItem>> = Arc::new(items.into_iter().map(|x| (x.id, x)).collect());
-    let item_icons: Arc<HashMap<u64, String>> = Arc::new(item_id_map.keys().map(|&id| (id, client.get_item_info(id).icon)).collect());
-
-    // Get the list of realms and create an empty price map so we can render pages while
-    // waiting for the auction results to be retrieved.
-    let realms = Arc::new(client.get_realms());
-    let connected_realms = BattleNetApiClient::process_connected_realms(&realms);
-    let price_map: Arc<BTreeMap<String, RwLock<CurrentRealmValues>>> =
-        Arc::new(realms.iter().map(|realm| (realm.slug.clone(), RwLock::new(CurrentRealmValues {
-            last_update: 0,
-            value_map: Arc::new(HashMap::new()),
-            sargerite_item_values: Arc::new(Vec::new()),
-            blood_item_values: Arc::new(Vec::new()),
-        }))).collect());
+    let price_history = match PriceHistory::new(Path::new(&config.data_dir).join("history")) {
+        Ok(price_history) => Arc::new(price_history),
+        Err(e) => return Err(format!("Unable to open price history in {}: {}", config.data_dir, e)),
+    };
+
+    // Read the catalog and grab its items' icon names and translations. Items are the same in
+    // every region, so one catalog and icon cache serves them all.
+    let (currencies, items) = match read_catalog(Path::new(&config.catalog_dir), &config.estimator) {
+        Ok(catalog) => catalog,
+        Err(e) => return Err(format!("Unable to use the catalog in {}: {}", config.catalog_dir, e)),
+    };
+    let saved_item_infos = snapshot_store.load_item_infos().unwrap_or_else(|e| {
+        println!("Ignoring saved item info: {}", e);
+        HashMap::new()
+    });
+    let item_infos = match item_infos_for(&client, &items, &locales, saved_item_infos) {
+        Ok(item_infos) => item_infos,
+        Err(e) => return Err(format!("Unable to download item info: {}", e)),
+    };
+    if let Err(e) = snapshot_store.save_item_infos(&item_infos) {
+        println!("Unable to save item info: {}", e);
+    }
+    let shared_catalog = Arc::new(SharedCatalog::new(Catalog::new(currencies, items, item_infos)));
+
+    // Get the list of realms in each region, and pick up the values we had before the restart.
+    let mut region_states: Vec<Arc<RegionState>> = Vec::new();
+    for &region in &config.regions {
+        match RegionState::new(region, Arc::new(client.for_region(region))) {
+            Ok(state) => {
+                if let Err(e) = snapshot_store.restore_region(&state) {
+                    println!("Unable to restore saved {} values: {}", region, e);
+                }
+                region_states.push(Arc::new(state));
+            },
+            Err(e) => return Err(format!("Unable to download {} realm list: {}", region, e)),
+        }
+    }
+    Ok(Setup {
+        client: client,
+        snapshot_store: snapshot_store,
+        price_history: price_history,
+        shared_catalog: shared_catalog,
+        region_states: region_states,
+        locales: locales,
+    })
+}
+
+/// `bloodmoney serve`: serves the site, fetching new auction data periodically. Only returns
+/// if it can't get started.
+fn serve(args: &[String]) -> Result<(), String> {
+    let (mut config, regions) = try!(read_config(args, &[]));
+    try!(set_regions(&mut config, &regions));
+    let Setup { client, snapshot_store, price_history, shared_catalog, region_states, locales } = try!(set_up(&config));
 
     // Set up our web-app.
-    let tera = Arc::new(Tera::new("templates/**/*"));
+    let site = Arc::new(Site {
+        catalog: shared_catalog.clone(),
+        locales: locales.clone(),
+        price_history: price_history.clone(),
+        fees: Arc::new(config.fees.clone()),
+        tera: Tera::new("templates/**/*"),
+    });
     let mut router = Router::new();
     {
-        let realms = realms.clone();
-        let tera = tera.clone();
-        router.get(format!("/{}", app_url_for_region(&locale)), move |_: &mut Request| {
+        let region_infos: Vec<&'static RegionInfo> = config.regions.iter().map(|region| region.info()).collect();
+        let site = site.clone();
+        router.get("/", move |_: &mut Request| {
             let mut context = Context::new();
-            context.add("realms", &realms);
-            context.add("is_eu", &(locale == Region::EU));
-            Ok(Response::with((ContentType::html().0, status::Ok, tera.render("index.html", context).unwrap())))
-        }, "index");
+            context.add("regions", &region_infos);
+            Ok(Response::with((ContentType::html().0, status::Ok, site.tera.render("regions.html", context).unwrap())))
+        }, "regions");
+    }
+    for state in &region_states {
+        add_region_routes(&mut router, state.clone(), site.clone());
+    }
+    api::add_api_routes(&mut router, region_states.clone(), site.clone());
+    let router = Arc::new(router);
+    let mut listeners = Vec::new();
+    for address in &config.bind {
+        let router = router.clone();
+        match Iron::new(move |request: &mut Request| router.handle(request)).http(address.as_str()) {
+            Ok(listening) => listeners.push(listening),
+            Err(e) => return Err(format!("Unable to serve on {}: {}", address, e)),
+        }
     }
+    println!("Ready for web traffic on {}.", config.bind.join(", "));
+
+    // Now that the webserver is up, periodically fetch
+    // new auction house data for each region.
+    let readers: Vec<_> = region_states.iter().cloned().map(|state| {
+        let shared_catalog = shared_catalog.clone();
+        let snapshot_store = snapshot_store.clone();
+        let price_history = price_history.clone();
+        let fees = site.fees.clone();
+        let (threads, period) = (config.fetch_threads, Duration::from_secs(config.fetch_period));
+        thread::spawn(move || read_region_forever(state, shared_catalog, fees, snapshot_store, price_history, threads, period))
+    }).collect();
+
+    // Pick up edits to the catalog as they're saved, revaluing what we last downloaded with it.
+    // An edit that doesn't check out, or that sells an item for a currency the realm page has no
+    // section for, leaves the current catalog in use.
     {
-        let price_map = price_map.clone();
-        let item_id_map = item_id_map.clone();
-        let realms = realms.clone();
-        let tera = tera.clone();
-        router.get(format!("/{}/:realm", app_url_for_region(&locale)), move |req : &mut Request| {
-            let realm = req.extensions.get::<Router>().unwrap().find("realm").unwrap();
-            if let Some(realm_prices_lock) = price_map.get(realm) {
-                let mut context = Context::new();
-                let realm_prices = realm_prices_lock.read().unwrap();
-
-                // Closure that processes the vectors of ItemValues into
-                // the HTML that we need.
-                // TODO: Things would actually be even cleaner probably if
-                // we did all this work in the background thread; it doesn't
-                // change per request. Then this router method would basically
-                // just take a read lock on the values, clone some things,
-                // and build the contexts.
-                let make_price_rows = |x: &Vec<ItemValue>| -> Vec<PriceRow> {
-                    let highest_value = match x.first() {
-                        Some(item_value) => item_value.value,
-                        None => 0
-                    };
-                    x.iter().map(|&ItemValue{id, value}| {
-                        let item_info = item_id_map.get(&id).unwrap();
-                        let gold = value / (10_000);
-                        let silver = (value - gold * 10_000) / 100;
-                        let copper = value - gold * 10_000 - silver * 100;
-                        let value_ratio = match highest_value {
-                            0u64 => 0u64,
-                            _ => value*100/highest_value,  // Percentile!
-                        };
-                        PriceRow {
-                            name: item_info.name.clone(),
-                            quantity: item_info.quantity,
-                            icon: item_icons.get(&id).unwrap().clone(),
-                            subtext: item_info.subtext.clone().unwrap_or(String::new()),
-                            vendor_type: item_info.vendor_type.clone(),
-                            value_ratio: value_ratio,
-                            gold: gold,
-                            silver: silver,
-                            copper: copper,
-                            mats: match item_info.mats {
-                                Some(ref mats_list) => mats_list.iter().map(|ref x| {
-                                    let value = realm_prices.value_map.get(&x.id).unwrap() * x.quantity;
-                                    let gold = value / (10_000);
-                                    let silver = (value - gold * 10_000) / 100;
-                                    let copper = value - gold * 10_000 - silver * 100;
-                                    Material {
-                                        name: item_id_map.get(&x.id).unwrap().name.clone(),
-                                        gold: gold,
-                                        silver: silver,
-                                        copper: copper,
-                                    }
-                                }).collect(),
-                                None => Vec::new(),
-                            },
-                        }
-                    }).collect()
-                };
-                let blood_price_rows = make_price_rows(&realm_prices.blood_item_values);
-                let sargerite_price_rows = make_price_rows(&realm_prices.sargerite_item_values);
-                context.add("realm_name", &realms.iter().find(|&realm_info| &realm_info.slug == realm).unwrap().name);
-                context.add("blood_price_rows", &blood_price_rows);
-                context.add("sargerite_price_rows", &sargerite_price_rows);
-                // TODO: Change this to something more human readable.
-                if realm_prices.last_update == 0 {
-                    context.add("update_age", &-1);
-                } else {
-                    context.add("update_age",
-                        &((SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() - realm_prices.last_update / 1000) / 60));
+        let fees = site.fees.clone();
+        let catalog_dir = PathBuf::from(&config.catalog_dir);
+        let estimator = config.estimator.clone();
+        thread::spawn(move || watch(&catalog_dir, || {
+            let checked = read_catalog(&catalog_dir, &estimator).and_then(|(currencies, items)| {
+                let unsectioned = try!(unsectioned_items(&catalog_dir, &currencies));
+                if !unsectioned.is_empty() {
+                    return Err(CatalogError::Invalid(unsectioned));
                 }
-                context.add("is_eu", &(locale == Region::EU));
-                Ok(Response::with((ContentType::html().0, status::Ok, tera.render("prices.html", context).unwrap())))
-            } else {
-                return Ok(Response::with(status::NotFound));
+                Ok((currencies, items))
+            });
+            let (currencies, items) = match checked {
+                Ok(catalog) => catalog,
+                Err(e) => {
+                    println!("Keeping the current catalog. Unable to use the one in {}: {}", catalog_dir.display(), e);
+                    return;
+                }
+            };
+            let item_infos = match item_infos_for(&client, &items, &locales, shared_catalog.get().item_infos.clone()) {
+                Ok(item_infos) => item_infos,
+                Err(e) => {
+                    println!("Keeping the current catalog. Unable to download item info: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = snapshot_store.save_item_infos(&item_infos) {
+                println!("Unable to save item info: {}", e);
             }
-        }, "realm-prices");
+            shared_catalog.replace(Catalog::new(currencies, items, item_infos));
+            let catalog = shared_catalog.get();
+            for state in &region_states {
+                revalue_region(state, &catalog.items, &fees);
+            }
+            println!("Reloaded the catalog from {}", catalog_dir.display());
+        }));
+    }
+    for reader in readers {
+        reader.join().unwrap();
     }
-    let http_result = Iron::new(router).http(format!("localhost:{}", match locale {
-        Region::US => 3000,
-        Region::EU => 3001,
-    }).as_str());
-    println!("Ready for web traffic.");
+    Ok(())
+}
 
-    // Now that the webserver is up, periodically fetch
-    // new auction house data.
-    let mut pool = Pool::new(NUM_AUCTION_DATA_THREADS);
-    loop {
-        let download_start = Instant::now();
-        let next_download_time = download_start + Duration::from_secs(RESULT_FETCH_PERIOD);
-        println!("Starting download of auction data.");
-        pool.scoped(|scope| {
-            for realm_list in &connected_realms {
-                // We have to move realm_list into the closure.
-                // Clone other values.
-                let client = client.clone();
-                let price_map = price_map.clone();
-                let item_id_map = item_id_map.clone();
-                scope.execute(move || {
-                    let lead_realm = realm_list.get(0).unwrap();
-                    let update_time: u64;
-                    let auction_listings: Vec<AuctionListing>;
-                    println!("Downloading {}", lead_realm);
-                    {
-                        let current_realm_values =
-                            price_map.get(lead_realm).unwrap().read().unwrap();
-                        match client.get_auction_listings(lead_realm, current_realm_values.last_update) {
-                            Some((ts, al)) => {
-                                update_time = ts;
-                                auction_listings = al;
-                            },
-                            None => return,
-                        }
-                    }
-                    let (value_map, blood_item_values, sargerite_item_values) = calculate_auction_values(&auction_listings, &item_id_map);
-                    // Make thread safe versions of the new values.
-                    let value_map = Arc::new(value_map);
-                    let blood_item_values = Arc::new(blood_item_values);
-                    let sargerite_item_values = Arc::new(sargerite_item_values);
-                    //let auction_values = Arc::new();
-                    for realm in realm_list {
-                        println!("Updating {}", realm);
-                        let mut current_realm_values =
-                            price_map.get(realm).unwrap().write().unwrap();
-                        current_realm_values.value_map = Arc::clone(&value_map);
-                        current_realm_values.blood_item_values = Arc::clone(&blood_item_values);
-                        current_realm_values.sargerite_item_values = Arc::clone(&sargerite_item_values);
-                        current_realm_values.last_update = update_time;
-                    }
-                })
+/// `bloodmoney fetch-once`: fetches every region's auction data once, saving the values and
+/// price history just like the server does, and writes the values to the output directory for
+/// publishing. Fails if any auction house couldn't be fetched.
+fn fetch_once(args: &[String]) -> Result<(), String> {
+    let (mut config, rest) = try!(read_config(args, &[]));
+    let buyout_listings = rest.iter().any(|arg| arg == "--buyout-listings");
+    let regions: Vec<String> = rest.into_iter().filter(|arg| arg != "--buyout-listings").collect();
+    try!(set_regions(&mut config, &regions));
+    let setup = try!(set_up(&config));
+    let fees = Arc::new(config.fees.clone());
+    let fetches: Vec<_> = setup.region_states.iter().cloned().map(|state| {
+        let shared_catalog = setup.shared_catalog.clone();
+        let fees = fees.clone();
+        let snapshot_store = setup.snapshot_store.clone();
+        let price_history = setup.price_history.clone();
+        let threads = config.fetch_threads;
+        thread::spawn(move || {
+            let mut pool = Pool::new(threads);
+            read_region_once(&state, &shared_catalog, &fees, &mut pool, &snapshot_store, &price_history)
+        })
+    }).collect();
+    let mut failures = Vec::new();
+    for fetch in fetches {
+        failures.extend(fetch.join().unwrap());
+    }
+    let output_dir = Path::new(&config.output_dir);
+    for state in &setup.region_states {
+        if let Err(e) = write_region(output_dir, state, buyout_listings) {
+            failures.push(format!("Unable to write {} values to {}: {}", state.region, config.output_dir, e));
+        }
+    }
+    if !failures.is_empty() {
+        return Err(format!("{} failures:\n  {}", failures.len(), failures.join("\n  ")));
+    }
+    println!("Wrote values to {}", config.output_dir);
+    Ok(())
+}
+
+/// `bloodmoney export`: writes the saved values of a region, or of one realm in it, to a file
+/// just like the site's CSV and TSV downloads.
+fn export_values(args: &[String]) -> Result<(), String> {
+    let (config, rest) = try!(read_config(args, &["format", "locale", "output"]));
+    let mut format = Format::Csv;
+    let mut locale = None;
+    let mut output = None;
+    let mut names = Vec::new();
+    for arg in rest {
+        if arg.starts_with("--format=") {
+            let extension = &arg["--format=".len()..];
+            format = try!(Format::from_extension(extension).ok_or(format!("Unknown format {}. {}", extension, USAGE)));
+        } else if arg.starts_with("--locale=") {
+            let name = &arg["--locale=".len()..];
+            if !is_supported(name) {
+                return Err(format!("Unknown locale {}. {}", name, USAGE));
             }
-            scope.join_all();
-        });
-        let download_end_time = Instant::now();
-        println!("Downloading all realms took {} seconds.", download_end_time.duration_since(download_start).as_secs());
-        if download_end_time < next_download_time {
-            println!("Sleeping for {}", next_download_time.duration_since(download_end_time).as_secs());
-            sleep(next_download_time.duration_since(download_end_time));
+            locale = Some(name.to_owned());
+        } else if arg.starts_with("--output=") {
+            output = Some(arg["--output=".len()..].to_owned());
+        } else if arg.starts_with("--") {
+            return Err(format!("Unknown option {}. {}", arg, USAGE));
+        } else {
+            names.push(arg);
         }
     }
+    let slug = match names.len() {
+        1 => None,
+        2 => Some(names[1].clone()),
+        _ => return Err(USAGE.to_owned()),
+    };
+    let region = try!(Region::from_name(&names[0]).ok_or(format!("Unknown region {}. {}", names[0], USAGE)));
+
+    // Everything comes from the data directory, so exporting needs neither credentials nor the
+    // network. Item names and sell prices are whatever item info was saved with the values.
+    let snapshot_store = try!(SnapshotStore::new(config.data_dir.as_str())
+        .map_err(|e| format!("Unable to open data directory {}: {}", config.data_dir, e)));
+    let (currencies, items) = try!(read_catalog(Path::new(&config.catalog_dir), &config.estimator)
+        .map_err(|e| format!("Unable to use the catalog in {}: {}", config.catalog_dir, e)));
+    let item_infos = snapshot_store.load_item_infos().unwrap_or_else(|e| {
+        println!("Ignoring saved item info: {}", e);
+        HashMap::new()
+    });
+    let catalog = Catalog::new(currencies, items, item_infos);
+    let client = Arc::new(BattleNetApiClient::new(config.credentials.clone(), region));
+    let state = match snapshot_store.load_region(region, client) {
+        Ok(Some(state)) => state,
+        Ok(None) => return Err(format!("No saved {} values in {}. Run fetch-once or serve with client_id and \
+                                        client_secret set first: they download the realm list and auctions that \
+                                        get exported.", region, config.data_dir)),
+        Err(e) => return Err(format!("Unable to restore saved {} values: {}", region, e)),
+    };
+    let locale = locale.unwrap_or(region.info().default_locale.to_owned());
+    let (file_name, out) = match slug {
+        Some(ref slug) if state.realm_values(slug).is_none() => return Err(format!("Unknown {} realm {}", region, slug)),
+        Some(ref slug) => (realm_file_name(&state, slug, format), realm_rows(&catalog, &state, slug, format, &locale)),
+        None => (region_file_name(&state, format), region_rows(&catalog, &state, format, &locale)),
+    };
+    let path = output.unwrap_or(file_name);
+    try!(File::create(&path).and_then(|mut f| f.write_all(out.as_bytes()))
+         .map_err(|e| format!("Unable to write {}: {}", path, e)));
+    println!("Wrote {}", path);
+    Ok(())
+}
+
+/// `bloodmoney catalog check`: reads and checks the configured catalog without starting the
+/// server, and optionally checks that every item resolves against recorded API responses
+/// (`--fixtures`) for the first configured region, or is in the saved item info cache (`--cached`).
+fn check_catalog(args: &[String]) -> Result<(), String> {
+    let (config, rest) = try!(read_config(args, &["fixtures"]));
+    let mut fixtures_dir = None;
+    let mut cached = false;
+    for arg in rest {
+        if arg.starts_with("--fixtures=") {
+            fixtures_dir = Some(arg["--fixtures=".len()..].to_owned());
+        } else if arg == "--cached" {
+            cached = true;
+        } else {
+            return Err(format!("Unknown option {}. {}", arg, USAGE));
+        }
+    }
+
+    let catalog_dir = PathBuf::from(&config.catalog_dir);
+    let (currencies, items) = match read_catalog(&catalog_dir, &config.estimator) {
+        Ok(catalog) => catalog,
+        Err(CatalogError::Invalid(problems)) => {
+            for problem in &problems {
+                println!("error: {}", problem);
+            }
+            return Err(format!("{} is invalid: {} errors", catalog_dir.display(), problems.len()));
+        },
+        Err(e) => return Err(format!("error: {}", e)),
+    };
+    let mut warnings = try!(unsectioned_items(&catalog_dir, &currencies).map_err(|e| format!("error: {}", e)));
+    warnings.extend(lint(&currencies, &items));
+    for warning in &warnings {
+        println!("warning: {}", warning);
+    }
+
+    let mut ids: Vec<u64> = items.keys().cloned().collect();
+    ids.sort();
+    let mut unresolved = Vec::new();
+    if let Some(fixtures_dir) = fixtures_dir {
+        let region = config.regions.first().cloned().unwrap_or(Region::US);
+        let mut client = BattleNetApiClient::with_transport(config.credentials.clone(), region,
+                                                            Arc::new(FixtureTransport::new(fixtures_dir)));
+        client.set_retry_policy(RetryPolicy { max_attempts: 1, initial_backoff: Duration::from_secs(0) });
+        let locales = vec![region.info().default_locale.to_owned()];
+        for &id in &ids {
+            if let Err(e) = client.get_item_info(id, &locales) {
+                unresolved.push(format!("item {} ({}) doesn't resolve against the fixtures: {}", id, items[&id].name, e));
+            }
+        }
+    }
+    if cached {
+        let cache = SnapshotStore::new(config.data_dir.as_str()).and_then(|store| store.load_item_infos());
+        match cache {
+            Ok(cache) => for &id in &ids {
+                if !cache.contains_key(&id) {
+                    unresolved.push(format!("item {} ({}) isn't in the item info cache in {}", id, items[&id].name, config.data_dir));
+                }
+            },
+            Err(e) => unresolved.push(format!("unable to read the item info cache in {}: {}", config.data_dir, e)),
+        }
+    }
+    for problem in &unresolved {
+        println!("error: {}", problem);
+    }
+    let summary = format!("{}: {} currencies, {} items, {} errors, {} warnings",
+                          catalog_dir.display(), currencies.len(), items.len(), unresolved.len(), warnings.len());
+    if !unresolved.is_empty() {
+        return Err(summary);
+    }
+    println!("{}", summary);
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(|arg| arg.as_str()) {
+        Some("serve") => serve(&args[1..]),
+        Some("fetch-once") => fetch_once(&args[1..]),
+        Some("export") => export_values(&args[1..]),
+        Some("catalog") if args.get(1).map(|arg| arg.as_str()) == Some("check") => check_catalog(&args[2..]),
+        _ => Err(USAGE.to_owned()),
+    };
+    if let Err(e) = result {
+        println!("{}", e);
+        process::exit(1);
+    }
 }
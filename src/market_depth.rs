@@ -0,0 +1,317 @@
+//! Models how much a player can actually make selling many units of an item, and
+//! splits a pile of vendor currency across items to make the most of it.
+//!
+//! We can't see sales, only listings, so we treat the units listed at or below an
+//! item's estimated price as what the market will buy before prices move. To sell
+//! your Nth unit you have to undercut the listing N units further down that stack,
+//! and once you're below the cheapest listing every further unit goes for a copper
+//! less than it.
+use std::cmp::{self, Ordering};
+use std::collections::BinaryHeap;
+
+use fees::FeeModel;
+
+/// How much we undercut a listing by, in copper per unit.
+pub const UNDERCUT: u64 = 1;
+
+/// The listings for an item that a seller competes with.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MarketDepth {
+    /// (quantity, unit price) listings sorted by unit price, with outliers removed.
+    listings: Vec<(u64, u64)>,
+    /// Units listed at or below the estimated price.
+    reference_units: u64,
+}
+
+impl MarketDepth {
+    /// Builds the depth of a market from its sorted listings and the price we estimated for it.
+    pub fn new(listings: Vec<(u64, u64)>, estimate: u64) -> MarketDepth {
+        let reference_units: u64 = listings.iter().take_while(|&&(_, unit_price)| unit_price <= estimate)
+            .map(|&(quantity, _)| quantity).sum();
+        MarketDepth {
+            listings: listings,
+            reference_units: reference_units,
+        }
+    }
+
+    /// Starts selling into this market.
+    pub fn seller(&self) -> Seller {
+        let mut running_sum: u64 = 0;
+        Seller {
+            depth: self,
+            ends: self.listings.iter().map(|&(quantity, _)| {
+                running_sum += quantity;
+                running_sum
+            }).collect(),
+            position: cmp::max(self.reference_units, 1),
+        }
+    }
+}
+
+/// Walks down a market's listings as a seller lists more and more units.
+pub struct Seller<'a> {
+    depth: &'a MarketDepth,
+    /// How many units are listed up to and including each listing.
+    ends: Vec<u64>,
+    /// Units listed at or below the price the seller has to undercut next.
+    position: u64,
+}
+
+impl<'a> Seller<'a> {
+    /// Sells `units` more units, returning what they bring in, in copper. Every unit undercut
+    /// in the same listing goes for the same price, so they're sold a listing at a time.
+    pub fn sell(&mut self, units: u64) -> u64 {
+        let mut revenue = 0;
+        let mut units_left = units;
+        while units_left > 0 {
+            let index = match self.ends.binary_search(&self.position) {
+                Ok(index) | Err(index) => index,
+            };
+            let unit_price = self.depth.listings.get(index)
+                .map(|&(_, unit_price)| unit_price.saturating_sub(UNDERCUT)).unwrap_or(0);
+            // Once we're down to the cheapest listing we stay there.
+            let listing_units = if index == 0 || index >= self.ends.len() {
+                units_left
+            } else {
+                self.position - self.ends[index - 1]
+            };
+            let sold = cmp::min(units_left, listing_units);
+            revenue += sold * unit_price;
+            units_left -= sold;
+            self.position = cmp::max(self.position.saturating_sub(sold), 1);
+        }
+        revenue
+    }
+}
+
+/// A vendor item we could spend currency on, a whole exchange at a time.
+#[derive(Debug)]
+pub struct Choice<'a> {
+    pub id: u64,
+    /// Currency one exchange costs.
+    pub cost: u64,
+    /// Units of the item one exchange gets.
+    pub quantity: u64,
+    /// Cost of the mats to craft one unit, in copper. 0 if it isn't crafted.
+    pub mat_cost: u64,
+    /// What a vendor pays for one unit. Deposits are based on it, and it's the least a unit is worth.
+    pub sell_price: u64,
+    pub depth: &'a MarketDepth,
+    pub fees: &'a FeeModel,
+}
+
+/// The marginal value of each of `exchanges` exchanges made for a choice, in copper, after fees.
+/// Values never increase, since every unit sold undercuts the last, until they bottom out
+/// at what a vendor pays.
+pub fn marginal_values(choice: &Choice, exchanges: u64) -> Vec<u64> {
+    let mut seller = choice.depth.seller();
+    let unit_costs = choice.mat_cost + choice.fees.expected_deposit_loss(choice.sell_price) as u64;
+    (0..exchanges).map(|_| {
+        let revenue = seller.sell(choice.quantity) * (100 - choice.fees.cut_percent) / 100;
+        let auction_value = revenue.saturating_sub(unit_costs * choice.quantity);
+        // Once the market's flooded, what's left can still go to a vendor, free of fees.
+        let vendor_value = choice.sell_price.saturating_sub(choice.mat_cost) * choice.quantity;
+        cmp::max(auction_value, vendor_value)
+    }).collect()
+}
+
+/// The marginal value of the `n`th unit of currency spent on a choice (counting from 1), as its
+/// share of the exchange it goes towards, given the values from `marginal_values`.
+pub fn currency_marginal_value(choice: &Choice, values: &[u64], n: u64) -> u64 {
+    values[((n - 1) / choice.cost) as usize] / choice.cost
+}
+
+/// How much currency to spend on a choice.
+#[derive(Debug, PartialEq)]
+pub struct Allocation {
+    pub id: u64,
+    pub exchanges: u64,
+    pub currency: u64,
+    /// What all of it is worth, in copper.
+    pub value: u64,
+    /// What the last exchange made for it was worth.
+    pub last_marginal_value: u64,
+}
+
+/// The next exchange we could make for a choice, ordered by its value per unit of currency.
+#[derive(PartialEq, Eq)]
+struct Candidate {
+    value: u64,
+    cost: u64,
+    choice: usize,
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Candidate) -> Ordering {
+        // Break ties towards the earlier choice so results are stable.
+        compare_fractions(self.value, self.cost, other.value, other.cost).then_with(|| other.choice.cmp(&self.choice))
+    }
+}
+
+/// Compares `a / b` with `c / d` exactly and without overflowing, however large the values:
+/// whole parts first, then the reciprocals of what's left over, as in a continued fraction.
+/// Neither `b` nor `d` may be 0.
+fn compare_fractions(a: u64, b: u64, c: u64, d: u64) -> Ordering {
+    let (whole_a, rest_a) = (a / b, a % b);
+    let (whole_c, rest_c) = (c / d, c % d);
+    if whole_a != whole_c {
+        return whole_a.cmp(&whole_c);
+    }
+    match (rest_a, rest_c) {
+        (0, 0) => Ordering::Equal,
+        (0, _) => Ordering::Less,
+        (_, 0) => Ordering::Greater,
+        // rest_a / b < rest_c / d exactly when d / rest_c < b / rest_a.
+        _ => compare_fractions(d, rest_c, b, rest_a),
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Candidate) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Splits `currency` across `choices` to get the most out of it, a whole exchange at a time.
+/// Marginal values only ever fall, so greedily making whichever exchange is worth the most per
+/// unit of currency is optimal when every exchange costs the same, and close otherwise.
+/// Currency that isn't worth anything anywhere, or too little for any exchange, is left
+/// unspent. Returns allocations for the choices that got any currency, most valuable first.
+pub fn allocate(choices: &[Choice], currency: u64) -> Vec<Allocation> {
+    let values: Vec<Vec<u64>> = choices.iter().map(|choice| marginal_values(choice, currency / choice.cost)).collect();
+    let mut spent: Vec<usize> = vec![0; choices.len()];
+    let mut remaining = currency;
+    let mut heap: BinaryHeap<Candidate> = values.iter().enumerate()
+        .filter(|&(_, choice_values)| !choice_values.is_empty())
+        .map(|(i, choice_values)| Candidate { value: choice_values[0], cost: choices[i].cost, choice: i })
+        .collect();
+    while let Some(candidate) = heap.pop() {
+        if candidate.value == 0 {
+            break;
+        }
+        if candidate.cost > remaining {
+            continue;
+        }
+        remaining -= candidate.cost;
+        spent[candidate.choice] += 1;
+        if let Some(&value) = values[candidate.choice].get(spent[candidate.choice]) {
+            heap.push(Candidate { value: value, cost: candidate.cost, choice: candidate.choice });
+        }
+    }
+    let mut allocations: Vec<Allocation> = choices.iter().enumerate().filter(|&(i, _)| spent[i] > 0).map(|(i, choice)| {
+        Allocation {
+            id: choice.id,
+            exchanges: spent[i] as u64,
+            currency: spent[i] as u64 * choice.cost,
+            value: values[i][..spent[i]].iter().sum(),
+            last_marginal_value: values[i][spent[i] - 1],
+        }
+    }).collect();
+    allocations.sort_by(|a, b| b.value.cmp(&a.value));
+    allocations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NO_FEES: FeeModel = FeeModel { cut_percent: 0, duration_hours: 12, expected_relists: 0.0 };
+
+    fn depth() -> MarketDepth {
+        // 5 units at 100, 5 at 200 and 90 at 1000; the estimate covers the first 10 units.
+        MarketDepth::new(vec![(5, 100), (5, 200), (90, 1000)], 200)
+    }
+
+    #[test]
+    fn test_selling_walks_down_the_listings() {
+        let depth = depth();
+        let mut seller = depth.seller();
+        assert_eq!(seller.sell(5), 5 * 199);
+        assert_eq!(seller.sell(5), 5 * 99);
+        // Below the cheapest listing everything goes for a copper less than it.
+        assert_eq!(seller.sell(2), 2 * 99);
+    }
+
+    #[test]
+    fn test_selling_many_units_at_once() {
+        let depth = depth();
+        let mut seller = depth.seller();
+        assert_eq!(seller.sell(7), 5 * 199 + 2 * 99);
+        assert_eq!(seller.sell(1000000000), 1000000000 * 99);
+    }
+
+    #[test]
+    fn test_compare_fractions() {
+        assert_eq!(compare_fractions(1, 3, 2, 6), Ordering::Equal);
+        assert_eq!(compare_fractions(2, 3, 3, 5), Ordering::Greater);
+        assert_eq!(compare_fractions(7, 10, 5, 7), Ordering::Less);
+        // Cross-multiplying these would overflow.
+        assert_eq!(compare_fractions(u64::max_value(), 3, u64::max_value() - 1, 3), Ordering::Greater);
+        assert_eq!(compare_fractions(u64::max_value() - 1, u64::max_value(), u64::max_value() - 2, u64::max_value() - 1),
+                   Ordering::Greater);
+    }
+
+    #[test]
+    fn test_marginal_values_fall_and_include_mats() {
+        let depth = depth();
+        let choice = Choice { id: 1, cost: 1, quantity: 2, mat_cost: 50, sell_price: 0, depth: &depth, fees: &NO_FEES };
+        assert_eq!(marginal_values(&choice, 4), vec![2 * 199 - 100, 2 * 199 - 100, 199 + 99 - 100, 2 * 99 - 100]);
+    }
+
+    #[test]
+    fn test_marginal_values_after_fees() {
+        let depth = MarketDepth::new(vec![(100, 10000)], 10000);
+        let fees = FeeModel { cut_percent: 5, duration_hours: 12, expected_relists: 1.0 };
+        // The cut takes 5% of 9999 and each unit risks a 15 copper deposit.
+        let choice = Choice { id: 1, cost: 1, quantity: 1, mat_cost: 0, sell_price: 100, depth: &depth, fees: &fees };
+        assert_eq!(marginal_values(&choice, 1), vec![9999 * 95 / 100 - 15]);
+    }
+
+    #[test]
+    fn test_marginal_values_bottom_out_at_vendor_price() {
+        let depth = depth();
+        let choice = Choice { id: 1, cost: 1, quantity: 5, mat_cost: 0, sell_price: 150, depth: &depth, fees: &NO_FEES };
+        assert_eq!(marginal_values(&choice, 3), vec![5 * 199, 5 * 150, 5 * 150]);
+    }
+
+    #[test]
+    fn test_currency_marginal_value() {
+        let depth = depth();
+        // 10 currency for 1 unit.
+        let choice = Choice { id: 1, cost: 10, quantity: 1, mat_cost: 0, sell_price: 0, depth: &depth, fees: &NO_FEES };
+        let values = marginal_values(&choice, 2);
+        assert_eq!(currency_marginal_value(&choice, &values, 1), 19);
+        assert_eq!(currency_marginal_value(&choice, &values, 10), 19);
+        assert_eq!(currency_marginal_value(&choice, &values, 11), 19);
+    }
+
+    #[test]
+    fn test_allocate_spreads_currency() {
+        let cheap = MarketDepth::new(vec![(100, 150)], 150);
+        let pricey = depth();
+        let choices = [
+            Choice { id: 1, cost: 1, quantity: 1, mat_cost: 0, sell_price: 0, depth: &pricey, fees: &NO_FEES },
+            Choice { id: 2, cost: 1, quantity: 1, mat_cost: 0, sell_price: 0, depth: &cheap, fees: &NO_FEES },
+        ];
+        // The first 5 units of the pricey item beat the cheap one, then it's the cheap one's turn.
+        assert_eq!(allocate(&choices, 8), vec![
+            Allocation { id: 1, exchanges: 5, currency: 5, value: 5 * 199, last_marginal_value: 199 },
+            Allocation { id: 2, exchanges: 3, currency: 3, value: 3 * 149, last_marginal_value: 149 },
+        ]);
+    }
+
+    #[test]
+    fn test_allocate_whole_exchanges() {
+        let gem = MarketDepth::new(vec![(100, 5000)], 5000);
+        let herb = MarketDepth::new(vec![(100, 150)], 150);
+        let choices = [
+            Choice { id: 1, cost: 10, quantity: 1, mat_cost: 0, sell_price: 0, depth: &gem, fees: &NO_FEES },
+            Choice { id: 2, cost: 1, quantity: 1, mat_cost: 0, sell_price: 0, depth: &herb, fees: &NO_FEES },
+        ];
+        // Gems are worth more per currency, but the last 5 currency can't buy one.
+        assert_eq!(allocate(&choices, 25), vec![
+            Allocation { id: 1, exchanges: 2, currency: 20, value: 2 * 4999, last_marginal_value: 4999 },
+            Allocation { id: 2, exchanges: 5, currency: 5, value: 5 * 149, last_marginal_value: 149 },
+        ]);
+    }
+}
@@ -0,0 +1,110 @@
+//! Drops listings that would skew prices before we estimate them: walls of
+//! 1 copper listings, reset attempts and absurdly large stacks.
+use std::cmp;
+
+/// Listings of more units than this are assumed to be mistakes or manipulation.
+const MAX_LISTING_QUANTITY: u64 = 100_000;
+
+/// Percentage of the units counted towards a price any one listing may make up. Blizzard no
+/// longer tells us who posted an auction, so this stands in for capping a single seller.
+const MAX_LISTING_SHARE: u64 = 20;
+
+/// Anything cheaper than the median divided by this is assumed to be a trick.
+const MEDIAN_FLOOR_DIVISOR: u64 = 10;
+
+/// Below this many listings there's too little to tell an outlier from the market.
+const MIN_LISTINGS_TO_FILTER: usize = 4;
+
+/// What's left of an item's listings after filtering, and how much was thrown out.
+#[derive(Debug, PartialEq)]
+pub struct FilteredListings {
+    /// (quantity, unit price) listings, still sorted by unit price, each held to its share of
+    /// the units. For estimating prices.
+    pub listings: Vec<(u64, u64)>,
+    /// The same listings with all their units. For modeling what the market will take, where
+    /// every unit listed counts no matter who listed it.
+    pub uncapped_listings: Vec<(u64, u64)>,
+    pub discarded_listings: u64,
+    /// Units thrown out, including those beyond a listing's share.
+    pub discarded_quantity: u64,
+}
+
+/// The price of the listing `percent` of the way through `listings`, counting each listing once.
+fn listing_percentile(listings: &[(u64, u64)], percent: usize) -> u64 {
+    listings[(listings.len() - 1) * percent / 100].1
+}
+
+/// Filters out outlying listings from (quantity, unit price) listings sorted by unit price.
+///
+/// Quartiles are taken over listings rather than units, so one enormous listing can't
+/// drag them around. Listings well outside the interquartile range or far below the
+/// median are dropped, then no listing may make up more than `MAX_LISTING_SHARE`
+/// percent of the units that remain.
+pub fn filter_outliers(listings: &[(u64, u64)]) -> FilteredListings {
+    let mut kept: Vec<(u64, u64)> = listings.iter().cloned()
+        .filter(|&(quantity, _)| quantity <= MAX_LISTING_QUANTITY)
+        .collect();
+    let mut uncapped_listings = None;
+    if kept.len() >= MIN_LISTINGS_TO_FILTER {
+        let q1 = listing_percentile(&kept, 25);
+        let median = listing_percentile(&kept, 50);
+        let q3 = listing_percentile(&kept, 75);
+        let iqr = q3 - q1;
+        let low_fence = cmp::max(q1.saturating_sub(iqr * 3 / 2), median / MEDIAN_FLOOR_DIVISOR);
+        let high_fence = q3 + iqr * 3;
+        kept.retain(|&(_, unit_price)| unit_price >= low_fence && unit_price <= high_fence);
+        uncapped_listings = Some(kept.clone());
+
+        let total_quantity: u64 = kept.iter().map(|&(quantity, _)| quantity).sum();
+        let max_quantity = cmp::max(total_quantity * MAX_LISTING_SHARE / 100, 1);
+        for listing in kept.iter_mut() {
+            listing.0 = cmp::min(listing.0, max_quantity);
+        }
+    }
+    let total_quantity: u64 = listings.iter().map(|&(quantity, _)| quantity).sum();
+    let kept_quantity: u64 = kept.iter().map(|&(quantity, _)| quantity).sum();
+    let uncapped_listings = uncapped_listings.unwrap_or_else(|| kept.clone());
+    FilteredListings {
+        discarded_listings: (listings.len() - kept.len()) as u64,
+        discarded_quantity: total_quantity - kept_quantity,
+        listings: kept,
+        uncapped_listings: uncapped_listings,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_few_listings_are_left_alone() {
+        let listings = vec![(5, 1), (1, 500000)];
+        assert_eq!(filter_outliers(&listings), FilteredListings {
+            listings: listings.clone(),
+            uncapped_listings: listings.clone(),
+            discarded_listings: 0,
+            discarded_quantity: 0,
+        });
+    }
+
+    #[test]
+    fn test_penny_wall_is_discarded() {
+        // A single wall of 1 copper listings under an otherwise steady market.
+        let listings = vec![(1000, 1), (10, 190000), (10, 200000), (10, 200000), (10, 210000), (10, 220000)];
+        let filtered = filter_outliers(&listings);
+        assert_eq!(filtered.discarded_listings, 1);
+        assert_eq!(filtered.discarded_quantity, 1000);
+        assert_eq!(filtered.listings[0], (10, 190000));
+    }
+
+    #[test]
+    fn test_absurd_quantities_and_big_listings() {
+        let listings = vec![(1000000, 100), (10, 100), (10, 110), (10, 120), (200, 120), (10, 130)];
+        let filtered = filter_outliers(&listings);
+        assert_eq!(filtered.discarded_listings, 1);
+        // The 200 unit listing counts for at most 20% of the 240 units left.
+        assert_eq!(filtered.listings[3], (48, 120));
+        assert_eq!(filtered.uncapped_listings[3], (200, 120));
+        assert_eq!(filtered.discarded_quantity, 1000000 + 152);
+    }
+}
@@ -0,0 +1,141 @@
+//! What `fetch-once` publishes: every auction house's values as JSON files, laid out for
+//! serving as static files. Each region gets a directory holding `realms.json`, which maps
+//! realm slugs to connected realm ids, `commodities.json` and `<connected realm id>.json`.
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
+use std::path::Path;
+
+use serde::Serialize;
+use serde_json;
+
+use price_history::PricePoint;
+use realm_reader::RegionState;
+use {CurrentRealmValues, ItemValue};
+
+/// One of an auction house's listings with a buyout.
+#[derive(Debug, Serialize)]
+struct ListingOutput {
+    quantity: u64,
+    /// The buyout in copper divided by the quantity, rounded down.
+    unit_price: u64,
+}
+
+/// The published values of one auction house.
+#[derive(Debug, Serialize)]
+struct AuctionHouseOutput<'a> {
+    region: &'static str,
+    /// None for the region's commodity auction house.
+    connected_realm_id: Option<u64>,
+    /// When its auctions were last updated, in milliseconds since the epoch. 0 if never.
+    last_update: u64,
+    /// Estimated unit prices in copper, keyed by item id.
+    prices: &'a HashMap<u64, u64>,
+    /// Values of the items sold for each currency, keyed by currency id. Best first.
+    values: &'a BTreeMap<String, Vec<ItemValue>>,
+    /// What was listed of each item, keyed by item id.
+    markets: &'a BTreeMap<u64, PricePoint>,
+    /// The listings with a buyout of each item, keyed by item id and cheapest first, if asked
+    /// for. Bid-only listings are left out. Empty for auction houses that weren't fetched this time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    buyout_listings: Option<BTreeMap<u64, Vec<ListingOutput>>>,
+}
+
+impl<'a> AuctionHouseOutput<'a> {
+    fn new(state: &RegionState, connected_realm_id: Option<u64>, values: &'a CurrentRealmValues,
+           buyout_listings: bool) -> AuctionHouseOutput<'a> {
+        AuctionHouseOutput {
+            region: state.region.info().name,
+            connected_realm_id: connected_realm_id,
+            last_update: values.last_update,
+            prices: &values.value_map,
+            values: &values.item_values,
+            markets: &values.markets,
+            buyout_listings: if buyout_listings {
+                Some(values.listings.iter().map(|(&id, listings)| {
+                    (id, listings.iter().map(|&(quantity, unit_price)| ListingOutput {
+                        quantity: quantity,
+                        unit_price: unit_price,
+                    }).collect())
+                }).collect())
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// Writes `value` as JSON to `path`, replacing it atomically so nothing serving the
+/// directory sees half a file.
+fn write_json<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let json = try!(serde_json::to_string(value).map_err(|e| io::Error::new(ErrorKind::InvalidData, e)));
+    let temp_path = path.with_extension("json.tmp");
+    {
+        let mut f = try!(File::create(&temp_path));
+        try!(f.write_all(json.as_bytes()));
+    }
+    fs::rename(&temp_path, path)
+}
+
+/// Writes the current values of every auction house in a region to `<dir>/<region>/`,
+/// with their listings that have a buyout too if `buyout_listings` is set.
+pub fn write_region(dir: &Path, state: &RegionState, buyout_listings: bool) -> io::Result<()> {
+    let region_dir = dir.join(state.region.info().name);
+    try!(fs::create_dir_all(&region_dir));
+    let realms: BTreeMap<&String, &u64> = state.realm_aliases.iter().collect();
+    try!(write_json(&region_dir.join("realms.json"), &realms));
+    {
+        let values = state.commodity_values.read().unwrap();
+        try!(write_json(&region_dir.join("commodities.json"), &AuctionHouseOutput::new(state, None, &values, buyout_listings)));
+    }
+    for (&id, values) in &state.price_map {
+        let values = values.read().unwrap();
+        try!(write_json(&region_dir.join(format!("{}.json", id)), &AuctionHouseOutput::new(state, Some(id), &values, buyout_listings)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::sync::Arc;
+
+    use serde_json::Value;
+
+    use test_support::{fixture_region_state, TempDir};
+    use super::*;
+
+    fn read(path: &Path) -> Value {
+        let mut json = String::new();
+        File::open(path).unwrap().read_to_string(&mut json).unwrap();
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn test_write_region() {
+        let state = fixture_region_state();
+        {
+            let mut values = state.price_map.get(&61).unwrap().write().unwrap();
+            values.last_update = 1509000000000;
+            values.value_map = Arc::new(vec![(124117, 5000)].into_iter().collect());
+            values.listings = Arc::new(vec![(124117, vec![(20, 5000)])].into_iter().collect());
+        }
+
+        let temp_dir = TempDir::new("blood-money-output-test");
+        let dir = temp_dir.path();
+        write_region(dir, &state, false).unwrap();
+        let values = read(&dir.join("us").join("61.json"));
+        assert_eq!(values.find("last_update").and_then(|v| v.as_u64()), Some(1509000000000));
+        assert_eq!(values.find("prices").and_then(|v| v.find("124117")).and_then(|v| v.as_u64()), Some(5000));
+        assert!(values.find("buyout_listings").is_none());
+        assert_eq!(read(&dir.join("us").join("commodities.json")).find("last_update").and_then(|v| v.as_u64()), Some(0));
+        assert!(read(&dir.join("us").join("realms.json")).as_object().unwrap().values().any(|id| id.as_u64() == Some(61)));
+
+        write_region(dir, &state, true).unwrap();
+        let values = read(&dir.join("us").join("61.json"));
+        let listings = values.find("buyout_listings").and_then(|v| v.find("124117")).and_then(|v| v.as_array()).unwrap();
+        assert_eq!(listings.len(), 1);
+        assert_eq!(listings[0].find("quantity").and_then(|v| v.as_u64()), Some(20));
+        assert_eq!(listings[0].find("unit_price").and_then(|v| v.as_u64()), Some(5000));
+    }
+}
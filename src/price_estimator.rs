@@ -0,0 +1,185 @@
+//! Ways of boiling an item's auction listings down to a single unit price.
+use std::cmp;
+use std::fmt;
+use std::sync::Arc;
+
+/// Estimates what an item is worth from its listings.
+pub trait PriceEstimator: fmt::Debug + Send + Sync {
+    /// Estimates a unit price from listings of (quantity, unit price), sorted by unit price.
+    /// `listings` is never empty.
+    fn estimate(&self, listings: &[(u64, u64)]) -> u64;
+
+    /// What the estimate means, for showing next to prices.
+    fn describe(&self) -> String;
+}
+
+/// The unit price `percent` of the way through every unit listed, cheapest first.
+fn unit_percentile(listings: &[(u64, u64)], percent: u64) -> u64 {
+    let total_quantity: u64 = listings.iter().map(|&(quantity, _)| quantity).sum();
+    let target_quantity = total_quantity * percent / 100;
+    let mut running_sum: u64 = 0;
+    listings.iter().find(|&&(quantity, _)| {
+        running_sum += quantity;
+        running_sum >= target_quantity
+    }).unwrap_or(&listings[listings.len() - 1]).1
+}
+
+/// The price below which a given percentage of the listed units sit.
+#[derive(Debug)]
+pub struct Percentile(pub u64);
+
+impl PriceEstimator for Percentile {
+    fn estimate(&self, listings: &[(u64, u64)]) -> u64 {
+        unit_percentile(listings, self.0)
+    }
+
+    fn describe(&self) -> String {
+        let suffix = match (self.0 % 10, self.0 % 100) {
+            (_, 11...13) => "th",
+            (1, _) => "st",
+            (2, _) => "nd",
+            (3, _) => "rd",
+            _ => "th",
+        };
+        format!("{}{} percentile", self.0, suffix)
+    }
+}
+
+/// The price of the middle unit listed, so big stacks count for more than small ones.
+#[derive(Debug)]
+pub struct WeightedMedian;
+
+impl PriceEstimator for WeightedMedian {
+    fn estimate(&self, listings: &[(u64, u64)]) -> u64 {
+        unit_percentile(listings, 50)
+    }
+
+    fn describe(&self) -> String {
+        "quantity-weighted median".to_owned()
+    }
+}
+
+/// The average unit price after dropping a given percentage of the cheapest and dearest units.
+#[derive(Debug)]
+pub struct TrimmedMean(pub u64);
+
+impl PriceEstimator for TrimmedMean {
+    fn estimate(&self, listings: &[(u64, u64)]) -> u64 {
+        let total_quantity: u64 = listings.iter().map(|&(quantity, _)| quantity).sum();
+        let trimmed = total_quantity * self.0 / 100;
+        let (low, high) = (trimmed, total_quantity - trimmed);
+        let mut start: u64 = 0;
+        let mut kept_quantity: u64 = 0;
+        let mut kept_cost: u64 = 0;
+        for &(quantity, unit_price) in listings {
+            // The units of this listing that fall between the trimmed ends.
+            let end = start + quantity;
+            let kept = cmp::min(end, high).saturating_sub(cmp::max(start, low));
+            kept_quantity += kept;
+            kept_cost += kept * unit_price;
+            start = end;
+        }
+        if kept_quantity == 0 {
+            WeightedMedian.estimate(listings)
+        } else {
+            kept_cost / kept_quantity
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("{}% trimmed mean", self.0)
+    }
+}
+
+/// The average unit price of buying a given number of the cheapest units, or everything
+/// listed if there are fewer.
+#[derive(Debug)]
+pub struct CheapestN(pub u64);
+
+impl PriceEstimator for CheapestN {
+    fn estimate(&self, listings: &[(u64, u64)]) -> u64 {
+        let mut bought: u64 = 0;
+        let mut cost: u64 = 0;
+        for &(quantity, unit_price) in listings {
+            let buying = cmp::min(quantity, self.0 - bought);
+            bought += buying;
+            cost += buying * unit_price;
+            if bought == self.0 {
+                break;
+            }
+        }
+        cost / cmp::max(bought, 1)
+    }
+
+    fn describe(&self) -> String {
+        format!("average of the cheapest {}", self.0)
+    }
+}
+
+/// What we price things with unless told otherwise.
+pub const DEFAULT_ESTIMATOR: &'static str = "percentile:5";
+
+/// Parses an estimator from a spec like "percentile:5", "weighted-median",
+/// "trimmed-mean:10" or "cheapest:100".
+pub fn parse_estimator(spec: &str) -> Result<Arc<PriceEstimator>, String> {
+    let mut parts = spec.splitn(2, ':');
+    let name = parts.next().unwrap();
+    let argument = match parts.next() {
+        Some(argument) => Some(try!(argument.parse::<u64>()
+            .map_err(|_| format!("Estimator {} needs a whole number, not {}", name, argument)))),
+        None => None,
+    };
+    match (name, argument) {
+        ("percentile", Some(percent)) if percent <= 100 => Ok(Arc::new(Percentile(percent))),
+        ("weighted-median", None) => Ok(Arc::new(WeightedMedian)),
+        ("trimmed-mean", Some(percent)) if percent < 50 => Ok(Arc::new(TrimmedMean(percent))),
+        ("cheapest", Some(quantity)) if quantity > 0 => Ok(Arc::new(CheapestN(quantity))),
+        _ => Err(format!("Unknown price estimator {}. Expected percentile:<0-100>, weighted-median, \
+                          trimmed-mean:<0-49> or cheapest:<quantity>", spec)),
+    }
+}
+
+/// The default estimator, for catalog items we haven't configured yet.
+pub fn default_estimator() -> Arc<PriceEstimator> {
+    parse_estimator(DEFAULT_ESTIMATOR).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 10 units at 100, 80 at 200 and 10 at 1000.
+    const LISTINGS: [(u64, u64); 3] = [(10, 100), (80, 200), (10, 1000)];
+
+    #[test]
+    fn test_percentile() {
+        assert_eq!(Percentile(5).estimate(&LISTINGS), 100);
+        assert_eq!(Percentile(50).estimate(&LISTINGS), 200);
+        assert_eq!(Percentile(95).estimate(&LISTINGS), 1000);
+        assert_eq!(Percentile(5).describe(), "5th percentile");
+        assert_eq!(Percentile(1).describe(), "1st percentile");
+        assert_eq!(Percentile(11).describe(), "11th percentile");
+    }
+
+    #[test]
+    fn test_other_estimators() {
+        assert_eq!(WeightedMedian.estimate(&LISTINGS), 200);
+        // Trimming 10% from each end leaves the 80 units at 200.
+        assert_eq!(TrimmedMean(10).estimate(&LISTINGS), 200);
+        assert_eq!(TrimmedMean(0).estimate(&LISTINGS), (1000 + 16000 + 10000) / 100);
+        assert_eq!(CheapestN(20).estimate(&LISTINGS), (1000 + 2000) / 20);
+        assert_eq!(CheapestN(1000).estimate(&LISTINGS), (1000 + 16000 + 10000) / 100);
+    }
+
+    #[test]
+    fn test_parse_estimator() {
+        assert_eq!(parse_estimator("percentile:5").unwrap().describe(), "5th percentile");
+        assert_eq!(parse_estimator("weighted-median").unwrap().describe(), "quantity-weighted median");
+        assert_eq!(parse_estimator("trimmed-mean:10").unwrap().describe(), "10% trimmed mean");
+        assert_eq!(parse_estimator("cheapest:100").unwrap().describe(), "average of the cheapest 100");
+        assert!(parse_estimator("percentile").is_err());
+        assert!(parse_estimator("percentile:101").is_err());
+        assert!(parse_estimator("cheapest:lots").is_err());
+        assert!(parse_estimator("mode").is_err());
+    }
+}
@@ -0,0 +1,280 @@
+//! Keeps a history of item prices for each auction house so we can show trends.
+//!
+//! Every item on every auction house gets its own directory of append-only JSON
+//! lines files, one per resolution. Points older than `RAW_RETENTION` are
+//! averaged into hourly points, and hourly points older than `HOURLY_RETENTION`
+//! into daily ones, so the files stay bounded.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::path::PathBuf;
+
+use serde_json;
+
+use region::Region;
+
+const HOUR: u64 = 60 * 60 * 1000;
+const DAY: u64 = 24 * HOUR;
+
+/// How long we keep every fetch's point, in milliseconds.
+const RAW_RETENTION: u64 = 7 * DAY;
+
+/// How long we keep hourly points, in milliseconds. Daily points are kept forever.
+const HOURLY_RETENTION: u64 = 90 * DAY;
+
+/// The state of an item's market on one auction house at one point in time.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PricePoint {
+    /// When Blizzard generated the auction data, in milliseconds since the epoch.
+    pub timestamp: u64,
+    /// Estimated unit price, see `price_estimator`.
+    pub price: u64,
+    /// Total number of items listed.
+    pub quantity: u64,
+    /// Number of separate listings.
+    pub listings: u64,
+    /// Listings left out of the price as outliers.
+    #[serde(default)]
+    pub discarded_listings: u64,
+    /// Units left out of the price as outliers.
+    #[serde(default)]
+    pub discarded_quantity: u64,
+    /// What one unit of vendor currency buys in this item, for the items the vendor sells.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<u64>,
+}
+
+/// An auction house whose prices we track.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuctionHouse {
+    /// The region-wide commodity auction house.
+    Commodities,
+    /// A connected realm's own auction house, by connected realm id.
+    ConnectedRealm(u64),
+}
+
+/// How finely a series file is sampled. Listed from coarsest to finest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Resolution {
+    Daily,
+    Hourly,
+    Raw,
+}
+
+impl Resolution {
+    fn file_name(&self) -> &'static str {
+        match *self {
+            Resolution::Daily => "daily.jsonl",
+            Resolution::Hourly => "hourly.jsonl",
+            Resolution::Raw => "raw.jsonl",
+        }
+    }
+}
+
+/// A directory of price series.
+pub struct PriceHistory {
+    dir: PathBuf,
+}
+
+impl PriceHistory {
+    /// Opens a price history in `dir`, creating the directory if needed.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> io::Result<PriceHistory> {
+        let dir = dir.into();
+        try!(fs::create_dir_all(&dir));
+        Ok(PriceHistory { dir: dir })
+    }
+
+    /// Records one fetch's prices for every item in `points`, keyed by item id.
+    /// Each auction house should only be recorded from one thread at a time.
+    pub fn record<'a, I>(&self, region: Region, auction_house: AuctionHouse, points: I) -> io::Result<()>
+        where I: IntoIterator<Item = (&'a u64, &'a PricePoint)> {
+        for (&item_id, point) in points {
+            let dir = self.series_dir(region, auction_house, item_id);
+            try!(fs::create_dir_all(&dir));
+            try!(append(&dir.join(Resolution::Raw.file_name()), &[point.clone()]));
+            try!(self.downsample(&dir, point.timestamp));
+        }
+        Ok(())
+    }
+
+    /// Every point we have for an item since `since`, oldest first.
+    /// Older points are hourly or daily averages.
+    pub fn series(&self, region: Region, auction_house: AuctionHouse, item_id: u64, since: u64)
+        -> io::Result<Vec<PricePoint>> {
+        let dir = self.series_dir(region, auction_house, item_id);
+        let mut series = Vec::new();
+        for resolution in &[Resolution::Daily, Resolution::Hourly, Resolution::Raw] {
+            let points = try!(read(&dir.join(resolution.file_name())));
+            // Downsampling appends to the coarser file before trimming the finer one,
+            // so skip anything a coarser file already covers.
+            let covered_until = series.last().map(|point: &PricePoint| point.timestamp);
+            series.extend(points.into_iter().filter(|point| {
+                point.timestamp >= since && covered_until.map_or(true, |t| point.timestamp > t)
+            }));
+        }
+        Ok(series)
+    }
+
+    fn series_dir(&self, region: Region, auction_house: AuctionHouse, item_id: u64) -> PathBuf {
+        let auction_house = match auction_house {
+            AuctionHouse::Commodities => "commodities".to_owned(),
+            AuctionHouse::ConnectedRealm(id) => id.to_string(),
+        };
+        self.dir.join(region.to_string()).join(auction_house).join(item_id.to_string())
+    }
+
+    /// Folds raw points that have aged out into hourly ones and hourly into daily,
+    /// relative to `now` (the newest point's timestamp).
+    fn downsample(&self, dir: &PathBuf, now: u64) -> io::Result<()> {
+        try!(move_older_points(dir, Resolution::Raw, Resolution::Hourly, HOUR, now.saturating_sub(RAW_RETENTION)));
+        move_older_points(dir, Resolution::Hourly, Resolution::Daily, DAY, now.saturating_sub(HOURLY_RETENTION))
+    }
+}
+
+/// Averages the points in `from` belonging to buckets that end before `cutoff` into
+/// one point per `bucket` milliseconds, appends them to `to` and drops them from `from`.
+fn move_older_points(dir: &PathBuf, from: Resolution, to: Resolution, bucket: u64, cutoff: u64) -> io::Result<()> {
+    let from_path = dir.join(from.file_name());
+    let points = try!(read(&from_path));
+    let cutoff = cutoff - cutoff % bucket;
+    if points.first().map_or(true, |point| point.timestamp >= cutoff) {
+        return Ok(());
+    }
+    let (old, recent): (Vec<PricePoint>, Vec<PricePoint>) = points.into_iter().partition(|point| point.timestamp < cutoff);
+    let mut averaged: Vec<PricePoint> = Vec::new();
+    let mut bucket_points: Vec<PricePoint> = Vec::new();
+    for point in old {
+        if bucket_points.first().map_or(false, |first| first.timestamp / bucket != point.timestamp / bucket) {
+            averaged.push(average(&bucket_points, bucket));
+            bucket_points.clear();
+        }
+        bucket_points.push(point);
+    }
+    averaged.push(average(&bucket_points, bucket));
+    try!(append(&dir.join(to.file_name()), &averaged));
+    rewrite(&from_path, &recent)
+}
+
+/// Averages the points in a bucket into a single point at the start of the bucket.
+fn average(points: &[PricePoint], bucket: u64) -> PricePoint {
+    let n = points.len() as u64;
+    PricePoint {
+        timestamp: points[0].timestamp - points[0].timestamp % bucket,
+        price: points.iter().map(|p| p.price).sum::<u64>() / n,
+        quantity: points.iter().map(|p| p.quantity).sum::<u64>() / n,
+        listings: points.iter().map(|p| p.listings).sum::<u64>() / n,
+        discarded_listings: points.iter().map(|p| p.discarded_listings).sum::<u64>() / n,
+        discarded_quantity: points.iter().map(|p| p.discarded_quantity).sum::<u64>() / n,
+        value: {
+            let values: Vec<u64> = points.iter().filter_map(|p| p.value).collect();
+            match values.len() {
+                0 => None,
+                len => Some(values.iter().sum::<u64>() / len as u64),
+            }
+        },
+    }
+}
+
+/// Reads a series file. A missing file is an empty series.
+fn read(path: &PathBuf) -> io::Result<Vec<PricePoint>> {
+    let f = match File::open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut points = Vec::new();
+    for line in BufReader::new(f).lines() {
+        let line = try!(line);
+        if line.is_empty() {
+            continue;
+        }
+        points.push(try!(serde_json::from_str(&line).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))));
+    }
+    Ok(points)
+}
+
+fn append(path: &PathBuf, points: &[PricePoint]) -> io::Result<()> {
+    let mut f = try!(OpenOptions::new().create(true).append(true).open(path));
+    f.write_all(try!(to_lines(points)).as_bytes())
+}
+
+/// Replaces a series file atomically.
+fn rewrite(path: &PathBuf, points: &[PricePoint]) -> io::Result<()> {
+    let temp_path = path.with_extension("tmp");
+    {
+        let mut f = try!(File::create(&temp_path));
+        try!(f.write_all(try!(to_lines(points)).as_bytes()));
+    }
+    fs::rename(&temp_path, path)
+}
+
+fn to_lines(points: &[PricePoint]) -> io::Result<String> {
+    let mut lines = String::new();
+    for point in points {
+        lines.push_str(&try!(serde_json::to_string(point).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))));
+        lines.push('\n');
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use test_support::TempDir;
+    use super::*;
+
+    fn point(timestamp: u64, price: u64) -> PricePoint {
+        PricePoint {
+            timestamp: timestamp,
+            price: price,
+            quantity: 10,
+            listings: 2,
+            discarded_listings: 0,
+            discarded_quantity: 0,
+            value: None,
+        }
+    }
+
+    fn history(name: &str) -> (TempDir, PriceHistory) {
+        let dir = TempDir::new(name);
+        let history = PriceHistory::new(dir.path()).unwrap();
+        (dir, history)
+    }
+
+    fn record(history: &PriceHistory, item_id: u64, point: PricePoint) {
+        let points: BTreeMap<u64, PricePoint> = vec![(item_id, point)].into_iter().collect();
+        history.record(Region::US, AuctionHouse::ConnectedRealm(61), &points).unwrap();
+    }
+
+    #[test]
+    fn test_record_and_read_series() {
+        let (dir, history) = history("blood-money-history-test");
+        record(&history, 124117, point(1000, 5000));
+        record(&history, 124117, point(2000, 6000));
+        record(&history, 124101, point(2000, 3000));
+        assert_eq!(history.series(Region::US, AuctionHouse::ConnectedRealm(61), 124117, 0).unwrap(),
+                   vec![point(1000, 5000), point(2000, 6000)]);
+        assert_eq!(history.series(Region::US, AuctionHouse::ConnectedRealm(61), 124117, 1500).unwrap(),
+                   vec![point(2000, 6000)]);
+        assert!(history.series(Region::US, AuctionHouse::Commodities, 124117, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_old_points_are_downsampled() {
+        let (dir, history) = history("blood-money-history-downsample-test");
+        let start = 100 * DAY;
+        // Two fetches in the same hour, then one a week and a bit later.
+        record(&history, 124117, point(start, 4000));
+        record(&history, 124117, point(start + HOUR / 2, 6000));
+        record(&history, 124117, point(start + RAW_RETENTION + 2 * HOUR, 8000));
+        assert_eq!(read(&dir.path().join("us/61/124117").join(Resolution::Raw.file_name())).unwrap(),
+                   vec![point(start + RAW_RETENTION + 2 * HOUR, 8000)]);
+        assert_eq!(history.series(Region::US, AuctionHouse::ConnectedRealm(61), 124117, 0).unwrap(),
+                   vec![point(start, 5000), point(start + RAW_RETENTION + 2 * HOUR, 8000)]);
+
+        // Far enough on, the hourly point becomes a daily one.
+        record(&history, 124117, point(start + HOURLY_RETENTION + 2 * DAY, 9000));
+        assert_eq!(read(&dir.path().join("us/61/124117").join(Resolution::Daily.file_name())).unwrap(),
+                   vec![point(start, 5000)]);
+    }
+}
@@ -1,2 +1,264 @@
-/// Used to read realm auction house data in a background
-/// thread periodically.
+//! Used to read realm auction house data in a background
+//! thread periodically.
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use scoped_threadpool::Pool;
+
+use battle_net_api_client::{ApiError, BattleNetApiClient, ConnectedRealm, RealmInfo};
+use catalog::SharedCatalog;
+use fees::FeeModel;
+use price_history::{AuctionHouse, PriceHistory};
+use region::Region;
+use snapshot_store::SnapshotStore;
+use {calculate_auction_values, group_listings, summarize_listings, CurrentRealmValues, VendorItem};
+
+/// Number of threads per region to use when fetching auction house results, unless configured otherwise.
+pub const DEFAULT_FETCH_THREADS: u32 = 5;
+
+/// Number of seconds between the starts of fetches of new auction results, unless configured otherwise.
+pub const DEFAULT_FETCH_PERIOD: u64 = 60 * 30;
+
+/// Everything we know about a single region's realms and their values.
+pub struct RegionState {
+    pub region: Region,
+    pub client: Arc<BattleNetApiClient<'static>>,
+    pub connected_realms: Vec<ConnectedRealm>,
+    /// Every realm in the region, sorted by name.
+    pub realms: Vec<RealmInfo>,
+    /// Realm slugs are aliases for the connected realm whose auction house they share.
+    pub realm_aliases: HashMap<String, u64>,
+    /// Values for each connected realm, keyed by connected realm id.
+    pub price_map: BTreeMap<u64, RwLock<CurrentRealmValues>>,
+    /// Commodities are sold region-wide, so they get a single set of values shared by every realm.
+    pub commodity_values: RwLock<CurrentRealmValues>,
+}
+
+impl RegionState {
+    /// Downloads the region's realm list and creates an empty price map so we can
+    /// render pages while waiting for the auction results to be retrieved.
+    pub fn new(region: Region, client: Arc<BattleNetApiClient<'static>>) -> Result<RegionState, ApiError> {
+        let connected_realms = try!(client.get_connected_realms());
+        Ok(RegionState::with_connected_realms(region, client, connected_realms))
+    }
+
+    /// Creates a region with an empty price map from a realm list we already have.
+    pub fn with_connected_realms(region: Region, client: Arc<BattleNetApiClient<'static>>,
+                                 connected_realms: Vec<ConnectedRealm>) -> RegionState {
+        let mut realms: Vec<RealmInfo> = connected_realms.iter().flat_map(|cr| cr.realms.iter().cloned()).collect();
+        realms.sort_by(|a, b| a.name.cmp(&b.name));
+        let realm_aliases = connected_realms.iter().flat_map(|cr| {
+            cr.realms.iter().map(move |realm| (realm.slug.clone(), cr.id))
+        }).collect();
+        let price_map = connected_realms.iter().map(|cr| (cr.id, RwLock::new(CurrentRealmValues::empty()))).collect();
+
+        RegionState {
+            region: region,
+            client: client,
+            connected_realms: connected_realms,
+            realms: realms,
+            realm_aliases: realm_aliases,
+            price_map: price_map,
+            commodity_values: RwLock::new(CurrentRealmValues::empty()),
+        }
+    }
+
+    /// Finds the values for the connected realm a realm belongs to.
+    pub fn realm_values(&self, slug: &str) -> Option<&RwLock<CurrentRealmValues>> {
+        self.realm_aliases.get(slug).and_then(|id| self.price_map.get(id))
+    }
+}
+
+/// Works out an auction house's values from the listings of a fetch, grouped by `group_listings`.
+/// `base_prices` are the unit prices of anything not sold on this auction house.
+fn value_auction_house(update_time: u64, listings: Arc<BTreeMap<u64, Vec<(u64, u64)>>>, items: &HashMap<u64, VendorItem>,
+                       commodities: bool, base_prices: &HashMap<u64, u64>, fees: &FeeModel) -> CurrentRealmValues {
+    let (mut markets, depths) = summarize_listings(update_time, &listings, items);
+    let (value_map, item_values) = calculate_auction_values(&markets, items, commodities, base_prices, fees);
+    // The price history keeps the value each vendor item came out at along with its price.
+    for item_value in item_values.values().flat_map(|values| values.iter()) {
+        if let Some(market) = markets.get_mut(&item_value.id) {
+            market.value = Some(item_value.value);
+        }
+    }
+    CurrentRealmValues {
+        last_update: update_time,
+        value_map: Arc::new(value_map),
+        markets: Arc::new(markets),
+        depths: Arc::new(depths),
+        item_values: Arc::new(item_values),
+        listings: listings,
+    }
+}
+
+/// Values a region's last fetched listings again with `items`, for when the catalog changes.
+/// Auction houses we haven't fetched since starting up have no listings to go on, so they keep
+/// their values until the next fetch.
+pub fn revalue_region(state: &RegionState, items: &HashMap<u64, VendorItem>, fees: &FeeModel) {
+    {
+        let mut commodity_values = state.commodity_values.write().unwrap();
+        if !commodity_values.listings.is_empty() {
+            *commodity_values = value_auction_house(commodity_values.last_update, commodity_values.listings.clone(), items,
+                                                    true, &HashMap::new(), fees);
+        }
+    }
+    let commodity_prices = state.commodity_values.read().unwrap().value_map.clone();
+    for realm_values_lock in state.price_map.values() {
+        let mut realm_values = realm_values_lock.write().unwrap();
+        if !realm_values.listings.is_empty() {
+            *realm_values = value_auction_house(realm_values.last_update, realm_values.listings.clone(), items,
+                                                false, &commodity_prices, fees);
+        }
+    }
+}
+
+/// Fetches new auction house data for every auction house in a region once with the catalog in
+/// use, using `pool` to fetch connected realms at the same time. Records the fetch in
+/// `price_history` and saves the results. Returns why each auction house that couldn't be
+/// fetched was skipped.
+///
+/// A fetch takes a while, so the catalog can be reloaded part way through. Each auction house
+/// checks for that while it holds the lock on its values, and values its listings again with the
+/// new catalog if so; a reload that comes later revalues them itself.
+pub fn read_region_once(state: &RegionState, shared_catalog: &SharedCatalog, fees: &FeeModel, pool: &mut Pool,
+                        snapshot_store: &SnapshotStore, price_history: &PriceHistory) -> Vec<String> {
+    let catalog = shared_catalog.get();
+    let items = &catalog.items;
+    let failures = Mutex::new(Vec::new());
+    println!("Starting download of {} auction data.", state.region);
+    // Commodities first, so per-realm crafting costs can use the new commodity prices.
+    {
+        let last_update = state.commodity_values.read().unwrap().last_update;
+        match state.client.get_commodity_listings(last_update) {
+            Ok(Some((update_time, auction_listings))) => {
+                let listings = Arc::new(group_listings(&auction_listings));
+                let values = value_auction_house(update_time, listings, items, true, &HashMap::new(), fees);
+                if let Err(e) = price_history.record(state.region, AuctionHouse::Commodities, &*values.markets) {
+                    println!("Unable to record {} commodity price history: {}", state.region, e);
+                }
+                println!("Updating {} commodities", state.region);
+                let mut commodity_values = state.commodity_values.write().unwrap();
+                *commodity_values = if shared_catalog.is_current(&catalog) {
+                    values
+                } else {
+                    value_auction_house(update_time, values.listings, &shared_catalog.get().items, true, &HashMap::new(), fees)
+                };
+            },
+            Ok(None) => (),
+            Err(e) => {
+                println!("Skipping {} commodities: {}", state.region, e);
+                failures.lock().unwrap().push(format!("{} commodities: {}", state.region, e));
+            },
+        }
+    }
+    // No point in downloading every realm's auctions if everything we value is a commodity.
+    if catalog.has_realm_items() {
+        let commodity_prices = state.commodity_values.read().unwrap().value_map.clone();
+        pool.scoped(|scope| {
+            for connected_realm in &state.connected_realms {
+                // We have to move connected_realm into the closure.
+                // Borrow other values.
+                let commodity_prices = &commodity_prices;
+                let failures = &failures;
+                scope.execute(move || {
+                    let realm_values_lock = state.price_map.get(&connected_realm.id).unwrap();
+                    println!("Downloading {} connected realm {}", state.region, connected_realm.id);
+                    let last_update = realm_values_lock.read().unwrap().last_update;
+                    let (update_time, auction_listings) = match state.client.get_auction_listings(connected_realm.id, last_update) {
+                        Ok(Some(update)) => update,
+                        Ok(None) => return,
+                        Err(e) => {
+                            println!("Skipping {} connected realm {}: {}", state.region, connected_realm.id, e);
+                            failures.lock().unwrap().push(format!("{} connected realm {}: {}", state.region, connected_realm.id, e));
+                            return;
+                        },
+                    };
+                    let listings = Arc::new(group_listings(&auction_listings));
+                    let values = value_auction_house(update_time, listings, items, false, commodity_prices, fees);
+                    if let Err(e) = price_history.record(state.region, AuctionHouse::ConnectedRealm(connected_realm.id),
+                                                         &*values.markets) {
+                        println!("Unable to record {} connected realm {} price history: {}", state.region, connected_realm.id, e);
+                    }
+                    println!("Updating {} connected realm {}", state.region, connected_realm.id);
+                    let mut realm_values = realm_values_lock.write().unwrap();
+                    *realm_values = if shared_catalog.is_current(&catalog) {
+                        values
+                    } else {
+                        let commodity_prices = state.commodity_values.read().unwrap().value_map.clone();
+                        value_auction_house(update_time, values.listings, &shared_catalog.get().items, false,
+                                            &commodity_prices, fees)
+                    };
+                })
+            }
+            scope.join_all();
+        });
+    }
+    if let Err(e) = snapshot_store.save_region(state) {
+        println!("Unable to save {} values: {}", state.region, e);
+    }
+    failures.into_inner().unwrap()
+}
+
+/// Periodically fetches new auction house data for a region with whatever catalog is in use,
+/// starting a fetch every `period` with `threads` connected realms fetched at once. Never returns.
+pub fn read_region_forever(state: Arc<RegionState>, shared_catalog: Arc<SharedCatalog>, fees: Arc<FeeModel>,
+                           snapshot_store: Arc<SnapshotStore>, price_history: Arc<PriceHistory>,
+                           threads: u32, period: Duration) {
+    let mut pool = Pool::new(threads);
+    loop {
+        let download_start = Instant::now();
+        let next_download_time = download_start + period;
+        read_region_once(&state, &shared_catalog, &fees, &mut pool, &snapshot_store, &price_history);
+        let download_end_time = Instant::now();
+        println!("Downloading all {} realms took {} seconds.", state.region, download_end_time.duration_since(download_start).as_secs());
+        if download_end_time < next_download_time {
+            println!("Sleeping for {}", next_download_time.duration_since(download_end_time).as_secs());
+            sleep(next_download_time.duration_since(download_end_time));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_support::{blood_catalog, fixture_region_state, TempDir};
+    use super::*;
+
+    #[test]
+    fn test_read_region_once_reports_failures() {
+        let state = fixture_region_state();
+        let catalog = SharedCatalog::new(blood_catalog(
+            r#"[{"id": 124105, "name": "Starlight Rose", "quantity": 10, "vendor_type": "blood", "commodity": false}]"#));
+
+        let dir = TempDir::new("blood-money-reader-test");
+        let snapshot_store = SnapshotStore::new(dir.path()).unwrap();
+        let price_history = PriceHistory::new(dir.path().join("history")).unwrap();
+        let failures = read_region_once(&state, &catalog, &FeeModel::default(), &mut Pool::new(1),
+                                        &snapshot_store, &price_history);
+        // Connected realm 1071 has no recorded auctions.
+        assert_eq!(failures.len(), 1, "{:?}", failures);
+        assert!(failures[0].contains("1071"));
+        assert!(state.price_map.get(&61).unwrap().read().unwrap().last_update > 0);
+    }
+
+    #[test]
+    fn test_revalue_region_values_new_items() {
+        let state = fixture_region_state();
+        let catalog = SharedCatalog::new(blood_catalog(
+            r#"[{"id": 124101, "name": "Aethril", "quantity": 10, "vendor_type": "blood"}]"#));
+
+        let dir = TempDir::new("blood-money-revalue-test");
+        let snapshot_store = SnapshotStore::new(dir.path()).unwrap();
+        let price_history = PriceHistory::new(dir.path().join("history")).unwrap();
+        read_region_once(&state, &catalog, &FeeModel::default(), &mut Pool::new(1), &snapshot_store, &price_history);
+        assert_eq!(state.commodity_values.read().unwrap().value_map.get(&124117), None);
+
+        // Dreamleaf wasn't in the catalog during the fetch, but its listings were kept.
+        catalog.replace(blood_catalog(
+            r#"[{"id": 124101, "name": "Aethril", "quantity": 10, "vendor_type": "blood"},
+                {"id": 124117, "name": "Dreamleaf", "quantity": 10, "vendor_type": "blood"}]"#));
+        revalue_region(&state, &catalog.get().items, &FeeModel::default());
+        assert_eq!(state.commodity_values.read().unwrap().value_map.get(&124117), Some(&5500));
+    }
+}
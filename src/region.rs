@@ -0,0 +1,137 @@
+//! Battle.net regions and everything that differs between them.
+use std::fmt;
+
+/// A Battle.net region. Each has its own API endpoints, realms and auction houses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Region {
+    US,
+    EU,
+    KR,
+    TW,
+    CN,
+}
+
+/// Describes how to talk to a region and how to present it.
+#[derive(Debug, Serialize)]
+pub struct RegionInfo {
+    /// Short name used on the command line, e.g. "us".
+    pub name: &'static str,
+    /// Human readable name for the region picker.
+    pub display_name: &'static str,
+    /// Host serving the Game Data API.
+    pub api_host: &'static str,
+    /// OAuth client-credentials token endpoint.
+    pub token_url: &'static str,
+    /// Namespace for dynamic Game Data resources such as realms and auctions.
+    pub dynamic_namespace: &'static str,
+    /// Namespace for static Game Data resources such as items.
+    pub static_namespace: &'static str,
+    /// Locale we request API responses in unless told otherwise.
+    pub default_locale: &'static str,
+    /// First component of the app's URLs for this region.
+    pub url_prefix: &'static str,
+    /// Host serving item icons.
+    pub render_host: &'static str,
+    /// Prepended to realm names on pages to make the region clear, e.g. "EU-".
+    pub display_prefix: &'static str,
+}
+
+static REGION_INFO: [RegionInfo; 5] = [
+    RegionInfo {
+        name: "us",
+        display_name: "Americas",
+        api_host: "us.api.blizzard.com",
+        token_url: "https://us.battle.net/oauth/token",
+        dynamic_namespace: "dynamic-us",
+        static_namespace: "static-us",
+        default_locale: "en_US",
+        url_prefix: "blood-money",
+        render_host: "render-us.worldofwarcraft.com",
+        display_prefix: "",
+    },
+    RegionInfo {
+        name: "eu",
+        display_name: "Europe",
+        api_host: "eu.api.blizzard.com",
+        token_url: "https://eu.battle.net/oauth/token",
+        dynamic_namespace: "dynamic-eu",
+        static_namespace: "static-eu",
+        default_locale: "en_GB",
+        url_prefix: "blood-money-eu",
+        render_host: "render-eu.worldofwarcraft.com",
+        display_prefix: "EU-",
+    },
+    RegionInfo {
+        name: "kr",
+        display_name: "Korea",
+        api_host: "kr.api.blizzard.com",
+        token_url: "https://kr.battle.net/oauth/token",
+        dynamic_namespace: "dynamic-kr",
+        static_namespace: "static-kr",
+        default_locale: "ko_KR",
+        url_prefix: "blood-money-kr",
+        render_host: "render-kr.worldofwarcraft.com",
+        display_prefix: "KR-",
+    },
+    RegionInfo {
+        name: "tw",
+        display_name: "Taiwan",
+        api_host: "tw.api.blizzard.com",
+        token_url: "https://tw.battle.net/oauth/token",
+        dynamic_namespace: "dynamic-tw",
+        static_namespace: "static-tw",
+        default_locale: "zh_TW",
+        url_prefix: "blood-money-tw",
+        render_host: "render-tw.worldofwarcraft.com",
+        display_prefix: "TW-",
+    },
+    RegionInfo {
+        name: "cn",
+        display_name: "China",
+        api_host: "gateway.battlenet.com.cn",
+        token_url: "https://www.battlenet.com.cn/oauth/token",
+        dynamic_namespace: "dynamic-cn",
+        static_namespace: "static-cn",
+        default_locale: "zh_CN",
+        url_prefix: "blood-money-cn",
+        render_host: "render.worldofwarcraft.com.cn",
+        display_prefix: "CN-",
+    },
+];
+
+impl Region {
+    /// Every region we know about.
+    pub fn all() -> [Region; 5] {
+        [Region::US, Region::EU, Region::KR, Region::TW, Region::CN]
+    }
+
+    /// Looks up a region by its short name, e.g. "eu".
+    pub fn from_name(name: &str) -> Option<Region> {
+        Region::all().iter().find(|r| r.info().name == name).cloned()
+    }
+
+    /// The descriptor for this region.
+    pub fn info(&self) -> &'static RegionInfo {
+        &REGION_INFO[*self as usize]
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.info().name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_region_info_matches_region() {
+        for region in Region::all().iter() {
+            assert_eq!(Region::from_name(region.info().name), Some(*region));
+        }
+        assert_eq!(Region::from_name("eu").unwrap().info().url_prefix, "blood-money-eu");
+        assert_eq!(Region::from_name("mars"), None);
+    }
+}
@@ -0,0 +1,240 @@
+//! Saves computed realm values and item info to disk so a restart
+//! doesn't leave every realm waiting on a fresh download.
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use serde_json;
+
+use battle_net_api_client::{BattleNetApiClient, ConnectedRealm, ItemInfo};
+use market_depth::MarketDepth;
+use price_history::PricePoint;
+use realm_reader::RegionState;
+use region::Region;
+use {CurrentRealmValues, ItemValue};
+
+/// An estimated unit price in a snapshot.
+#[derive(Debug, Serialize, Deserialize)]
+struct UnitPrice {
+    id: u64,
+    value: u64,
+}
+
+/// The on-disk form of `CurrentRealmValues`.
+#[derive(Debug, Serialize, Deserialize)]
+struct ValuesSnapshot {
+    last_update: u64,
+    value_map: Vec<UnitPrice>,
+    #[serde(default)]
+    markets: Vec<(u64, PricePoint)>,
+    #[serde(default)]
+    depths: Vec<(u64, MarketDepth)>,
+    /// (currency id, values) for every currency.
+    #[serde(default)]
+    item_values: Vec<(String, Vec<ItemValue>)>,
+    /// Where snapshots from before currencies came from the catalog kept their values.
+    #[serde(default, skip_serializing)]
+    blood_item_values: Vec<ItemValue>,
+    #[serde(default, skip_serializing)]
+    sargerite_item_values: Vec<ItemValue>,
+}
+
+impl<'a> From<&'a CurrentRealmValues> for ValuesSnapshot {
+    fn from(values: &'a CurrentRealmValues) -> ValuesSnapshot {
+        ValuesSnapshot {
+            last_update: values.last_update,
+            value_map: values.value_map.iter().map(|(&id, &value)| UnitPrice { id: id, value: value }).collect(),
+            markets: values.markets.iter().map(|(&id, market)| (id, market.clone())).collect(),
+            depths: values.depths.iter().map(|(&id, depth)| (id, depth.clone())).collect(),
+            item_values: values.item_values.iter().map(|(currency, values)| (currency.clone(), values.clone())).collect(),
+            blood_item_values: Vec::new(),
+            sargerite_item_values: Vec::new(),
+        }
+    }
+}
+
+impl From<ValuesSnapshot> for CurrentRealmValues {
+    fn from(snapshot: ValuesSnapshot) -> CurrentRealmValues {
+        let mut item_values: BTreeMap<String, Vec<ItemValue>> = snapshot.item_values.into_iter().collect();
+        for (currency, values) in vec![("blood", snapshot.blood_item_values), ("sargerite", snapshot.sargerite_item_values)] {
+            if !values.is_empty() {
+                item_values.entry(currency.to_owned()).or_insert(values);
+            }
+        }
+        CurrentRealmValues {
+            last_update: snapshot.last_update,
+            value_map: Arc::new(snapshot.value_map.into_iter().map(|unit_price| (unit_price.id, unit_price.value)).collect()),
+            markets: Arc::new(snapshot.markets.into_iter().collect()),
+            depths: Arc::new(snapshot.depths.into_iter().collect()),
+            item_values: Arc::new(item_values),
+            listings: Arc::new(BTreeMap::new()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ConnectedRealmSnapshot {
+    id: u64,
+    values: ValuesSnapshot,
+}
+
+/// Everything we save about a region.
+#[derive(Debug, Serialize, Deserialize)]
+struct RegionSnapshot {
+    commodities: ValuesSnapshot,
+    connected_realms: Vec<ConnectedRealmSnapshot>,
+    /// The region's realm list, so it can be loaded without downloading it. Empty in snapshots
+    /// from before we kept it.
+    #[serde(default)]
+    realm_list: Vec<ConnectedRealm>,
+}
+
+/// Puts the values in a region's snapshot in place. Connected realms that no longer exist are ignored.
+fn restore_values(state: &RegionState, snapshot: RegionSnapshot) {
+    *state.commodity_values.write().unwrap() = CurrentRealmValues::from(snapshot.commodities);
+    for connected_realm in snapshot.connected_realms {
+        if let Some(values) = state.price_map.get(&connected_realm.id) {
+            *values.write().unwrap() = CurrentRealmValues::from(connected_realm.values);
+        }
+    }
+}
+
+/// A directory of JSON snapshots: one file per region plus one for item info.
+pub struct SnapshotStore {
+    dir: PathBuf,
+}
+
+impl SnapshotStore {
+    /// Opens a snapshot store in `dir`, creating the directory if needed.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> io::Result<SnapshotStore> {
+        let dir = dir.into();
+        try!(fs::create_dir_all(&dir));
+        Ok(SnapshotStore { dir: dir })
+    }
+
+    /// Saves the current values of every connected realm in a region.
+    pub fn save_region(&self, state: &RegionState) -> io::Result<()> {
+        let snapshot = RegionSnapshot {
+            commodities: ValuesSnapshot::from(&*state.commodity_values.read().unwrap()),
+            connected_realms: state.price_map.iter().map(|(&id, values)| ConnectedRealmSnapshot {
+                id: id,
+                values: ValuesSnapshot::from(&*values.read().unwrap()),
+            }).collect(),
+            realm_list: state.connected_realms.clone(),
+        };
+        self.write(&format!("{}.json", state.region), &snapshot)
+    }
+
+    /// Restores a region's values from its last snapshot, if there is one.
+    /// Connected realms that no longer exist are ignored.
+    pub fn restore_region(&self, state: &RegionState) -> io::Result<()> {
+        if let Some(snapshot) = try!(self.read(&format!("{}.json", state.region))) {
+            restore_values(state, snapshot);
+        }
+        Ok(())
+    }
+
+    /// Loads a region from its last snapshot alone, realm list and all, for working with saved
+    /// values without going to the API. None if there's no snapshot, or it's from before we kept
+    /// the realm list.
+    pub fn load_region(&self, region: Region, client: Arc<BattleNetApiClient<'static>>) -> io::Result<Option<RegionState>> {
+        let mut snapshot: RegionSnapshot = match try!(self.read(&format!("{}.json", region))) {
+            Some(snapshot) => snapshot,
+            None => return Ok(None),
+        };
+        if snapshot.realm_list.is_empty() {
+            return Ok(None);
+        }
+        let state = RegionState::with_connected_realms(region, client, mem::replace(&mut snapshot.realm_list, Vec::new()));
+        restore_values(&state, snapshot);
+        Ok(Some(state))
+    }
+
+    /// Saves the item info we've downloaded.
+    pub fn save_item_infos(&self, item_infos: &HashMap<u64, ItemInfo>) -> io::Result<()> {
+        self.write("items.json", &item_infos.values().collect::<Vec<_>>())
+    }
+
+    /// Loads previously saved item info, keyed by item id. Empty if there's none.
+    pub fn load_item_infos(&self) -> io::Result<HashMap<u64, ItemInfo>> {
+        let item_infos: Vec<ItemInfo> = try!(self.read("items.json")).unwrap_or(Vec::new());
+        Ok(item_infos.into_iter().map(|item_info| (item_info.id, item_info)).collect())
+    }
+
+    /// Writes `value` to `name`, replacing it atomically so a crash can't leave half a snapshot.
+    fn write<T: Serialize>(&self, name: &str, value: &T) -> io::Result<()> {
+        let json = try!(serde_json::to_string(value).map_err(|e| io::Error::new(ErrorKind::InvalidData, e)));
+        let temp_path = self.dir.join(format!("{}.tmp", name));
+        {
+            let mut f = try!(File::create(&temp_path));
+            try!(f.write_all(json.as_bytes()));
+            try!(f.sync_all());
+        }
+        fs::rename(&temp_path, self.dir.join(name))
+    }
+
+    /// Reads `name`, or None if it doesn't exist.
+    fn read<T: Deserialize>(&self, name: &str) -> io::Result<Option<T>> {
+        let mut json = String::new();
+        match File::open(self.dir.join(name)) {
+            Ok(mut f) => try!(f.read_to_string(&mut json)),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        serde_json::from_str(&json).map(Some).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json;
+
+    use test_support::{fixture_client, fixture_region_state, TempDir};
+    use super::*;
+
+    #[test]
+    fn test_region_round_trip() {
+        let dir = TempDir::new("blood-money-snapshot-test");
+        let store = SnapshotStore::new(dir.path()).unwrap();
+
+        let state = fixture_region_state();
+        {
+            let mut values = state.price_map.get(&61).unwrap().write().unwrap();
+            values.last_update = 1509000000000;
+            values.value_map = Arc::new(vec![(124117, 5000)].into_iter().collect());
+            values.item_values = Arc::new(vec![
+                ("blood".to_owned(), vec![ItemValue { id: 124117, value: 50000, net_value: 47000, vendor_it: false }]),
+            ].into_iter().collect());
+        }
+        store.save_region(&state).unwrap();
+
+        let restored = fixture_region_state();
+        store.restore_region(&restored).unwrap();
+        let values = restored.price_map.get(&61).unwrap().read().unwrap();
+        assert_eq!(values.last_update, 1509000000000);
+        assert_eq!(values.value_map.get(&124117), Some(&5000));
+        assert_eq!(values.item_values.get("blood").unwrap()[0].value, 50000);
+        assert_eq!(restored.price_map.get(&1071).unwrap().read().unwrap().last_update, 0);
+
+        let loaded = store.load_region(Region::US, Arc::new(fixture_client())).unwrap().unwrap();
+        assert_eq!(loaded.realms.len(), restored.realms.len());
+        assert_eq!(loaded.realm_aliases, restored.realm_aliases);
+        assert_eq!(loaded.price_map.get(&61).unwrap().read().unwrap().value_map.get(&124117), Some(&5000));
+        assert!(store.load_region(Region::EU, Arc::new(fixture_client())).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_restore_values_from_before_catalog_currencies() {
+        let snapshot: ValuesSnapshot = serde_json::from_str(r#"{"last_update": 1, "value_map": [],
+            "blood_item_values": [{"id": 124117, "value": 50000}], "sargerite_item_values": []}"#).unwrap();
+        let values = CurrentRealmValues::from(snapshot);
+        assert_eq!(values.item_values.get("blood").unwrap()[0].id, 124117);
+        assert!(!values.item_values.contains_key("sargerite"));
+    }
+}
@@ -0,0 +1,86 @@
+//! Setup shared by the tests: a client and region state answering from the API responses
+//! recorded in `tests/fixtures/us`, scratch directories, and catalogs to value things with.
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json;
+use tera::Tera;
+
+use battle_net_api_client::{BattleNetApiClient, Credentials, RetryPolicy};
+use catalog::{read_catalog, Catalog, SharedCatalog, DEFAULT_CATALOG_DIR};
+use fees::FeeModel;
+use http_transport::FixtureTransport;
+use price_estimator::DEFAULT_ESTIMATOR;
+use price_history::PriceHistory;
+use realm_reader::RegionState;
+use region::Region;
+use {Site, VendorItem};
+
+/// The one item with recorded item info.
+pub const STARLIGHT_ROSE: u64 = 124105;
+
+/// A US client answering from the recordings in `tests/fixtures/us` without retrying.
+pub fn fixture_client() -> BattleNetApiClient<'static> {
+    let credentials = Credentials { client_id: "id".to_owned(), client_secret: "secret".to_owned() };
+    let mut client = BattleNetApiClient::with_transport(credentials, Region::US,
+        Arc::new(FixtureTransport::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/us"))));
+    client.set_retry_policy(RetryPolicy { max_attempts: 1, initial_backoff: Duration::from_secs(0) });
+    client
+}
+
+/// The US connected realms as recorded, with nothing downloaded for them yet.
+pub fn fixture_region_state() -> RegionState {
+    RegionState::new(Region::US, Arc::new(fixture_client())).unwrap()
+}
+
+/// An empty directory in the system temp directory, removed with everything in it when dropped.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Makes `name` in the system temp directory, clearing out whatever an earlier run left there.
+    pub fn new(name: &str) -> TempDir {
+        let path = env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path: path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// A catalog of blood items, given as they would be in `catalog/items.json`.
+pub fn blood_catalog(items: &str) -> Catalog {
+    let currencies = serde_json::from_str(r#"[{"id": "blood", "name": "Blood of Sargeras", "icon": "blood", "description": ""}]"#).unwrap();
+    let items: Vec<VendorItem> = serde_json::from_str(items).unwrap();
+    Catalog::new(currencies, items.into_iter().map(|item| (item.id, item)).collect(), HashMap::new())
+}
+
+/// The site as served with the shipped catalog, keeping price history in `history_dir`. Only
+/// Starlight Rose has item info.
+pub fn fixture_site(history_dir: &Path) -> Site {
+    let (currencies, items) = read_catalog(&Path::new(env!("CARGO_MANIFEST_DIR")).join(DEFAULT_CATALOG_DIR),
+                                           DEFAULT_ESTIMATOR).unwrap();
+    let item_infos = vec![(STARLIGHT_ROSE, fixture_client().get_item_info(STARLIGHT_ROSE, &[]).unwrap())]
+        .into_iter().collect();
+    Site {
+        catalog: Arc::new(SharedCatalog::new(Catalog::new(currencies, items, item_infos))),
+        locales: vec!["en_US".to_owned()],
+        price_history: Arc::new(PriceHistory::new(history_dir).unwrap()),
+        fees: Arc::new(FeeModel::default()),
+        tera: Tera::new(concat!(env!("CARGO_MANIFEST_DIR"), "/templates/**/*")),
+    }
+}